@@ -1,4 +1,6 @@
-use anchor_lang::prelude::*;
+use anchor_lang::{prelude::*, solana_program::hash::hashv};
+
+use crate::error::Errors;
 
 #[account]
 pub struct Vault {
@@ -6,28 +8,526 @@ pub struct Vault {
     pub vault_id: u64,
     pub owner: Pubkey,
     pub is_frozen: bool,
+    pub bump: u8,
+    /// Trade, listing or auction currently holding this vault frozen, only it can unfreeze the vault
+    pub active_trade: Option<Pubkey>,
 }
 
 impl GetSize for Vault {
     fn get_size() -> usize {
-        return 32 + 8 + 32 + 1;
+        return 32 + 8 + 32 + 1 + 1 + 33;
+    }
+}
+
+impl Vault {
+    /// Runs `f` with the vault PDA's signer seeds, built from the stored bump
+    pub fn with_signer_seeds<T>(&self, f: impl FnOnce(&[&[&[u8]]]) -> Result<T>) -> Result<T> {
+        let creator_bytes = self.creator.to_bytes();
+        let vault_id_bytes = self.vault_id.to_be_bytes();
+        f(&[&[
+            b"vault",
+            creator_bytes.as_ref(),
+            vault_id_bytes.as_ref(),
+            &[self.bump],
+        ]])
+    }
+
+    /// Whether `signer` owns the vault or holds a live grant with all of `permissions`
+    pub fn can_manage(
+        &self,
+        vault: Pubkey,
+        signer: Pubkey,
+        delegate: Option<&VaultDelegate>,
+        permissions: u8,
+    ) -> bool {
+        self.owner == signer
+            || matches!(delegate, Some(d) if d.allows(&vault, &self.owner, &signer, permissions))
+    }
+
+    pub fn freeze_for(&mut self, holder: Pubkey) {
+        self.is_frozen = true;
+        self.active_trade = Some(holder);
+    }
+
+    pub fn release_from(&mut self, holder: Pubkey) -> Result<()> {
+        require!(
+            self.active_trade == Some(holder),
+            Errors::VaultTradeMismatch
+        );
+        self.is_frozen = false;
+        self.active_trade = None;
+        Ok(())
+    }
+}
+
+/// Delegate permissions, combined into `VaultDelegate::permissions`
+pub const DELEGATE_DEPOSIT: u8 = 1 << 0;
+pub const DELEGATE_WITHDRAW: u8 = 1 << 1;
+/// Attach the vault to trades and multi-trades
+pub const DELEGATE_TRADE: u8 = 1 << 2;
+/// Close the vault and its empty token accounts, the vault's lamports can only go to the owner
+pub const DELEGATE_CLOSE: u8 = 1 << 3;
+pub const DELEGATE_ALL: u8 = DELEGATE_DEPOSIT | DELEGATE_WITHDRAW | DELEGATE_TRADE | DELEGATE_CLOSE;
+
+/**
+ * Lets a wallet other than the owner act on a vault, limited to a set of permissions.
+ * The grant lapses once the vault changes hands or the expiry passes.
+ */
+#[account]
+pub struct VaultDelegate {
+    pub vault: Pubkey,
+    pub delegate: Pubkey,
+    /// Owner that granted it
+    pub owner: Pubkey,
+    pub permissions: u8,
+    pub expires_at: Option<i64>,
+    pub bump: u8,
+}
+
+impl GetSize for VaultDelegate {
+    fn get_size() -> usize {
+        32 + 32 + 32 + 1 + 9 + 1
+    }
+}
+
+impl VaultDelegate {
+    pub fn allows(&self, vault: &Pubkey, owner: &Pubkey, signer: &Pubkey, permissions: u8) -> bool {
+        self.vault == *vault
+            && self.owner == *owner
+            && self.delegate == *signer
+            && self.permissions & permissions == permissions
+            && match self.expires_at {
+                Some(expires_at) => {
+                    matches!(Clock::get(), Ok(clock) if clock.unix_timestamp < expires_at)
+                }
+                None => true,
+            }
+    }
+}
+
+/// Upper bound on distinct assets a vault can track in its manifest
+pub const MAX_MANIFEST_ENTRIES: usize = 32;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AssetKind {
+    Nft,
+    PNft,
+    CNft,
+    Spl,
+    Sol,
+    Core,
+}
+
+/**
+ * One asset held by a vault.
+ * `id` is the mint for token assets, the asset id for cNFTs, the asset account for Core assets
+ * and the System Program id for SOL.
+ */
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub kind: AssetKind,
+    pub id: Pubkey,
+    pub amount: u64,
+}
+
+impl GetSize for ManifestEntry {
+    fn get_size() -> usize {
+        1 + 32 + 8
+    }
+}
+
+/**
+ * On-chain record of what a Vault holds, kept in sync by the deposit and withdraw instructions.
+ * Lets a trade counterparty see what they'd receive without trusting an indexer.
+ */
+#[account]
+pub struct VaultManifest {
+    pub vault: Pubkey,
+    pub bump: u8,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl GetSize for VaultManifest {
+    fn get_size() -> usize {
+        32 + 1 + 4 + MAX_MANIFEST_ENTRIES * ManifestEntry::get_size()
     }
 }
 
+impl VaultManifest {
+    pub fn add(&mut self, kind: AssetKind, id: Pubkey, amount: u64) -> Result<()> {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.id == id) {
+            entry.amount = entry
+                .amount
+                .checked_add(amount)
+                .ok_or(Errors::ManifestOverflow)?;
+            return Ok(());
+        }
+        require!(
+            self.entries.len() < MAX_MANIFEST_ENTRIES,
+            Errors::ManifestFull
+        );
+        self.entries.push(ManifestEntry { kind, id, amount });
+        Ok(())
+    }
+
+    // Assets that were sent to the vault outside of the deposit instructions aren't tracked,
+    // so removing more than was recorded just drops the entry instead of failing the withdraw.
+    pub fn remove(&mut self, id: Pubkey, amount: u64) {
+        if let Some(pos) = self.entries.iter().position(|e| e.id == id) {
+            let remaining = self.entries[pos].amount.saturating_sub(amount);
+            if remaining == 0 {
+                self.entries.remove(pos);
+            } else {
+                self.entries[pos].amount = remaining;
+            }
+        }
+    }
+
+    /// Mints of the NFTs/pNFTs held, the assets carrying on-chain royalty metadata
+    /// Core assets keep royalties in a collection plugin the program doesn't read,
+    /// so they aren't paid royalties when vault ownership is sold
+    pub fn royalty_mints(&self) -> Vec<Pubkey> {
+        self.entries
+            .iter()
+            .filter(|e| e.kind == AssetKind::Nft || e.kind == AssetKind::PNft)
+            .map(|e| e.id)
+            .collect()
+    }
+
+    /// SOL is swept along with the vault's own lamports, so it doesn't keep a vault open
+    pub fn holds_assets(&self) -> bool {
+        self.entries.iter().any(|e| e.kind != AssetKind::Sol)
+    }
+
+    /// Commitment to the vault's current contents, used to pin trade terms at lock time
+    pub fn terms_hash(&self) -> [u8; 32] {
+        let entries = self.entries.try_to_vec().unwrap();
+        hashv(&[self.vault.as_ref(), &entries]).to_bytes()
+    }
+}
+
+/**
+ * Lifecycle of a TradeAccount
+ * Open -> Joined -> Attached -> Locked
+ * Settling, cancelling or expiring a trade closes its account, so there's no terminal state to store.
+ */
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TradeStatus {
+    /// Waiting for an acceptor
+    Open,
+    /// Acceptor joined, waiting for both vaults
+    Joined,
+    /// Both vaults attached, waiting for both sides to lock
+    Attached,
+    /// Both sides locked, ready to confirm
+    Locked,
+}
+
+/// Fixed price the acceptor pays instead of attaching vaults
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TradePayment {
+    /// None pays in lamports
+    pub mint: Option<Pubkey>,
+    pub amount: u64,
+}
+
+impl GetSize for TradePayment {
+    fn get_size() -> usize {
+        33 + 8
+    }
+}
+
+/// Upper bound on vaults each side can attach to a two-party trade
+pub const MAX_VAULTS_PER_SIDE: usize = 4;
+
 #[account]
 pub struct TradeAccount {
     pub trade_id: u64,
     pub creator_key: Pubkey,
-    pub creator_vault_key: Option<Pubkey>,
+    pub creator_vault_keys: Vec<Pubkey>,
     pub creator_confirmed: bool,
     pub acceptor_key: Option<Pubkey>,
-    pub acceptor_vault_key: Option<Pubkey>,
+    pub acceptor_vault_keys: Vec<Pubkey>,
     pub acceptor_confirmed: bool,
+    /// Hash over the acceptor vaults' manifests when the creator locked
+    pub creator_terms: [u8; 32],
+    /// Hash over the creator vaults' manifests when the acceptor locked
+    pub acceptor_terms: [u8; 32],
+    pub status: TradeStatus,
+    /// Unix timestamp after which anyone can unwind the trade with expire_trade
+    pub expires_at: Option<i64>,
+    /// If set, only this wallet can join as the acceptor (OTC deals agreed off-chain)
+    pub allowed_acceptor: Option<Pubkey>,
+    /// Set for one-sided trades: the acceptor pays this instead of attaching vaults
+    pub payment: Option<TradePayment>,
+    /// Whether the acceptor's payment is currently held by the trade account
+    pub payment_escrowed: bool,
+    pub bump: u8,
 }
 
 impl GetSize for TradeAccount {
     fn get_size() -> usize {
-        return 8 + 32 + 32 + 32 + 32 + 3 + 2;
+        return 8
+            + 32
+            + (4 + MAX_VAULTS_PER_SIDE * 32)
+            + 1
+            + 33
+            + (4 + MAX_VAULTS_PER_SIDE * 32)
+            + 1
+            + 32
+            + 32
+            + 1
+            + 9
+            + 33
+            + (1 + TradePayment::get_size())
+            + 1
+            + 1;
+    }
+}
+
+impl TradeAccount {
+    /// Runs `f` with the trade PDA's signer seeds, built from the stored bump
+    pub fn with_signer_seeds<T>(&self, f: impl FnOnce(&[&[&[u8]]]) -> Result<T>) -> Result<T> {
+        let creator_bytes = self.creator_key.to_bytes();
+        let trade_id_bytes = self.trade_id.to_be_bytes();
+        f(&[&[
+            b"trade",
+            creator_bytes.as_ref(),
+            trade_id_bytes.as_ref(),
+            &[self.bump],
+        ]])
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    /// Creator vaults followed by acceptor vaults
+    pub fn all_vault_keys(&self) -> Vec<Pubkey> {
+        self.creator_vault_keys
+            .iter()
+            .chain(self.acceptor_vault_keys.iter())
+            .copied()
+            .collect()
+    }
+
+    /// Derives the live status from who has joined, attached and locked
+    pub fn refresh_status(&mut self) {
+        self.status = if self.acceptor_key.is_none() {
+            TradeStatus::Open
+        } else if self.creator_vault_keys.is_empty()
+            || (self.payment.is_none() && self.acceptor_vault_keys.is_empty())
+        {
+            TradeStatus::Joined
+        } else if self.creator_confirmed && self.acceptor_confirmed {
+            TradeStatus::Locked
+        } else {
+            TradeStatus::Attached
+        };
+    }
+}
+
+/// Upper bound on participants in a multi-party trade
+pub const MAX_PARTICIPANTS: usize = 5;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Participant {
+    pub key: Pubkey,
+    pub vault_key: Option<Pubkey>,
+    /// Routing table entry: index of the participant who receives this participant's vault
+    pub receiver: u8,
+    pub confirmed: bool,
+    /// Hash over every attached vault's manifest when this participant locked
+    pub terms: [u8; 32],
+}
+
+impl GetSize for Participant {
+    fn get_size() -> usize {
+        32 + 33 + 1 + 1 + 32
+    }
+}
+
+/**
+ * Trade between more than two wallets, each putting in one vault.
+ * Every participant and the routing table are fixed at creation, so it starts out Joined.
+ */
+#[account]
+pub struct MultiTradeAccount {
+    pub trade_id: u64,
+    pub creator_key: Pubkey,
+    pub participants: Vec<Participant>,
+    pub status: TradeStatus,
+    pub expires_at: Option<i64>,
+    pub bump: u8,
+}
+
+impl GetSize for MultiTradeAccount {
+    fn get_size() -> usize {
+        8 + 32 + 4 + MAX_PARTICIPANTS * Participant::get_size() + 1 + 9 + 1
+    }
+}
+
+impl MultiTradeAccount {
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    /// Every attached vault, in participant order
+    pub fn vault_keys(&self) -> Vec<Pubkey> {
+        self.participants
+            .iter()
+            .filter_map(|p| p.vault_key)
+            .collect()
+    }
+
+    pub fn participant_index(&self, key: &Pubkey) -> Option<usize> {
+        self.participants.iter().position(|p| p.key == *key)
+    }
+
+    /// Derives the live status from who has attached and locked
+    pub fn refresh_status(&mut self) {
+        self.status = if self.participants.iter().any(|p| p.vault_key.is_none()) {
+            TradeStatus::Joined
+        } else if self.participants.iter().all(|p| p.confirmed) {
+            TradeStatus::Locked
+        } else {
+            TradeStatus::Attached
+        };
+    }
+}
+
+/**
+ * Fixed-price sell order for a whole Vault.
+ * The vault stays frozen while listed, buy_vault pays the seller and hands the vault over.
+ */
+#[account]
+pub struct Listing {
+    pub vault: Pubkey,
+    pub seller: Pubkey,
+    /// None is priced in lamports
+    pub payment_mint: Option<Pubkey>,
+    pub price: u64,
+    pub bump: u8,
+}
+
+impl GetSize for Listing {
+    fn get_size() -> usize {
+        32 + 32 + 33 + 8 + 1
+    }
+}
+
+/**
+ * English auction for a whole Vault, bids are in lamports and escrowed by this account.
+ * The vault stays frozen until the auction is settled or cancelled.
+ */
+#[account]
+pub struct Auction {
+    pub vault: Pubkey,
+    pub seller: Pubkey,
+    pub reserve_price: u64,
+    pub min_increment: u64,
+    pub end_time: i64,
+    pub highest_bid: u64,
+    pub highest_bidder: Option<Pubkey>,
+    pub bump: u8,
+}
+
+impl GetSize for Auction {
+    fn get_size() -> usize {
+        32 + 32 + 8 + 8 + 8 + 8 + 33 + 1
+    }
+}
+
+/**
+ * Standing offer to buy any NFT from a verified collection, the price is escrowed in lamports by this account.
+ * Whoever fills it sends the NFT straight into the buyer's vault.
+ */
+#[account]
+pub struct Offer {
+    pub buyer: Pubkey,
+    pub offer_id: u64,
+    /// Vault that receives the NFT
+    pub vault: Pubkey,
+    /// Verified collection key the NFT must belong to
+    pub collection: Pubkey,
+    pub price: u64,
+    pub bump: u8,
+}
+
+impl GetSize for Offer {
+    fn get_size() -> usize {
+        32 + 8 + 32 + 32 + 8 + 1
+    }
+}
+
+/// Fees are in basis points of the payment
+pub const MAX_BPS: u16 = 10_000;
+
+/// Protocol fee per venue, in basis points of the payment
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FeeSchedule {
+    /// Payment legs of trades
+    pub trade_bps: u16,
+    /// Fixed-price listings
+    pub listing_bps: u16,
+    /// Winning auction bids
+    pub auction_bps: u16,
+    /// Filled collection offers
+    pub offer_bps: u16,
+}
+
+impl FeeSchedule {
+    pub fn is_valid(&self) -> bool {
+        [
+            self.trade_bps,
+            self.listing_bps,
+            self.auction_bps,
+            self.offer_bps,
+        ]
+        .iter()
+        .all(|bps| *bps <= MAX_BPS)
+    }
+}
+
+/// Asset programs vaults accept deposits from.
+/// Withdrawals are never gated on this so assets can't get stuck in a vault.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AssetPrograms {
+    pub token: bool,
+    pub token_2022: bool,
+    pub bubblegum: bool,
+    pub core: bool,
+}
+
+impl AssetPrograms {
+    /// Token and Token-2022 are toggled separately for the interface based deposits
+    pub fn allows_token_program(&self, program: &Pubkey) -> bool {
+        (*program == anchor_spl::token::ID && self.token)
+            || (*program == anchor_spl::token_2022::ID && self.token_2022)
+    }
+}
+
+/**
+ * Global program settings, a single PDA created by the upgrade authority.
+ * The protocol fee is taken from every SOL/SPL leg of a trade or sale.
+ * While paused every instruction except the admin ones fails.
+ */
+#[account]
+pub struct Config {
+    pub admin: Pubkey,
+    pub paused: bool,
+    /// Wallet collecting protocol fees, SPL fees go to its token accounts
+    pub fee_receiver: Pubkey,
+    pub fees: FeeSchedule,
+    /// Require royalties for every NFT/pNFT in a vault sold for a payment
+    pub royalties_enforced: bool,
+    pub asset_programs: AssetPrograms,
+    pub bump: u8,
+}
+
+impl GetSize for Config {
+    fn get_size() -> usize {
+        32 + 1 + 32 + (2 * 4) + 1 + 4 + 1
     }
 }
 
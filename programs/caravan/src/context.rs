@@ -1,15 +1,23 @@
 use anchor_lang::prelude::*;
 use anchor_spl::{token::{Token, TokenAccount, Mint}, associated_token::AssociatedToken};
+use anchor_spl::token_2022::Token2022;
+use anchor_spl::token_interface::{self, TokenInterface};
 use spl_account_compression::{program::SplAccountCompression, Noop};
-use mpl_bubblegum::state::TreeConfig;
+use mpl_bubblegum::state::{metaplex_adapter::MetadataArgs, TreeConfig};
 
-use crate::{account::*, pnft::AuthorizationDataLocal};
+use crate::{account::*, error::Errors, metaplex_core::mpl_core, pnft::AuthorizationDataLocal, program::Caravan};
 
 #[derive(Accounts)]
 #[instruction(vault_id:u64)]
 pub struct CreateVault<'info> {
   #[account(mut)]
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
 
   #[account(
@@ -23,61 +31,133 @@ pub struct CreateVault<'info> {
     bump,
     space=8+Vault::get_size()
   )]
-  pub vault: Account<'info, Vault>
+  pub vault: Account<'info, Vault>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump,
+    space=8+VaultManifest::get_size()
+  )]
+  pub manifest: Account<'info, VaultManifest>
 }
 
 #[derive(Accounts)]
 #[instruction(vault_id:u64)]
-pub struct WithdrawNFT<'info> {
+pub struct DepositNFT<'info> {
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.allows_token_program(&token_program.key()) @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
   /**
-   * Only the Owner can Widraw NFTs from this Vault
-   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   * Only the Owner or a Delegate can Deposit into this Vault
+   * Cannot deposit if the vault is frozen (currently being used in a Trade)
    */
   #[account(
-    constraint = (vault.owner.key() == payer.key()) && (!vault.is_frozen)
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_DEPOSIT) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
   )]
   pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 
   #[account(mut)]
-  pub vault_ata: Account<'info, TokenAccount>,
-  #[account(mut)]
-  pub receiver_ata: Account<'info, TokenAccount>,
-  pub token_program: Program<'info, Token>,
+  pub payer_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+  #[account(
+    mut,
+    constraint = vault_ata.owner == vault.key()
+  )]
+  pub vault_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+  #[account(address = vault_ata.mint)]
+  pub mint: InterfaceAccount<'info, token_interface::Mint>,
+  /// CHECK: The mint's Token Metadata PDA, it may not exist. Only a mint with metadata is recorded as an NFT
+  #[account(
+    seeds=[
+      mpl_token_metadata::state::PREFIX.as_bytes(),
+      mpl_token_metadata::id().as_ref(),
+      mint.key().as_ref(),
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump,
+  )]
+  pub metadata: UncheckedAccount<'info>,
+  pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
 #[instruction(vault_id: u64, authorization_data: Option<AuthorizationDataLocal>, rules_acc_present: bool)]
-pub struct WithdrawPNFT<'info> {
+pub struct DepositPNFT<'info> {
+  #[account(mut)]
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.token @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
   /**
-   * Only the Owner can Widraw NFTs from this Vault
-   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   * Only the Owner or a Delegate can Deposit into this Vault
+   * Cannot deposit if the vault is frozen (currently being used in a Trade)
    */
   #[account(
-    constraint = (vault.owner.key() == payer.key()) && (!vault.is_frozen),
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_DEPOSIT) && (!vault.is_frozen),
     seeds=[
       b"vault",
-      payer.key().to_bytes().as_ref(),
+      vault.creator.to_bytes().as_ref(),
       vault_id.to_be_bytes().as_ref(),
     ],
-    bump,
+    bump = vault.bump,
   )]
   pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 
   #[account(mut)]
-  pub vault_ata: Account<'info, TokenAccount>,
+  pub payer_ata: Account<'info, TokenAccount>,
+  /// CHECK: Vault's ATA for the mint, will get initialized by mpl program
   #[account(
-    mut, //will get initialized by mpl program
-    associated_token::mint = nft_mint,
-    associated_token::authority = receiver    
+    mut,
+    address = anchor_spl::associated_token::get_associated_token_address(&vault.key(), &nft_mint.key())
   )]
-  pub receiver_ata: Account<'info, TokenAccount>,
-  /// CHECK: The owner of the receiver ATA, passed into transfer() call
-  pub receiver: UncheckedAccount<'info>,
+  pub vault_ata: UncheckedAccount<'info>,
   pub nft_mint: Account<'info, Mint>,
+  /// CHECK: Checked with seeds constraint
   #[account(
     mut,
     seeds=[
@@ -108,7 +188,7 @@ pub struct WithdrawPNFT<'info> {
         mpl_token_metadata::id().as_ref(),
         nft_mint.key().as_ref(),
         mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
-        vault_ata.key().as_ref()
+        payer_ata.key().as_ref()
     ],
     seeds::program = mpl_token_metadata::id(),
     bump
@@ -116,17 +196,17 @@ pub struct WithdrawPNFT<'info> {
   pub owner_token_record: UncheckedAccount<'info>,
   /// CHECK: seeds below
   #[account(mut,
-          seeds=[
-              mpl_token_metadata::state::PREFIX.as_bytes(),
-              mpl_token_metadata::id().as_ref(),
-              nft_mint.key().as_ref(),
-              mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
-              receiver_ata.key().as_ref()
-          ],
-          seeds::program = mpl_token_metadata::id(),
-          bump
-      )]
-  pub receiver_token_record: UncheckedAccount<'info>,
+    seeds=[
+        mpl_token_metadata::state::PREFIX.as_bytes(),
+        mpl_token_metadata::id().as_ref(),
+        nft_mint.key().as_ref(),
+        mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
+        vault_ata.key().as_ref()
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump
+  )]
+  pub vault_token_record: UncheckedAccount<'info>,
   /// CHECK: Auth Rules Program
   #[account(address = mpl_token_auth_rules::id())]
   pub auth_rules_program: UncheckedAccount<'info>,
@@ -139,26 +219,43 @@ pub struct WithdrawPNFT<'info> {
 
 #[derive(Accounts)]
 #[instruction(vault_id: u64, root: [u8; 32], data_hash: [u8; 32], creator_hash: [u8; 32], nonce: u64, index: u32)]
-pub struct WithdrawCNFT<'info> {
+pub struct DepositCNFT<'info> {
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.bubblegum @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
   /**
-   * Only the Owner can Widraw NFTs from this Vault
-   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   * Only the Owner or a Delegate can Deposit into this Vault
+   * Cannot deposit if the vault is frozen (currently being used in a Trade)
    */
   #[account(
-    constraint = (vault.owner.key() == payer.key()) && (!vault.is_frozen),
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_DEPOSIT) && (!vault.is_frozen),
     seeds=[
       b"vault",
-      payer.key().to_bytes().as_ref(),
+      vault.creator.to_bytes().as_ref(),
       vault_id.to_be_bytes().as_ref(),
     ],
-    bump,
+    bump = vault.bump,
   )]
   pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 
-  /// CHECK: Can be anything, could be a PDA
-  pub receiver: UncheckedAccount<'info>,
   #[account(
     seeds = [merkle_tree.key().as_ref()],
     bump, 
@@ -166,8 +263,6 @@ pub struct WithdrawCNFT<'info> {
   )]
   /// CHECK: This account is neither written to nor read from.
   pub tree_authority: Account<'info, TreeConfig>,
-  /// CHECK: This account is neither written to nor read from.
-  pub new_leaf_owner: UncheckedAccount<'info>, // receiver
   #[account(mut)]
   /// CHECK: This account is modified in the downstream program
   pub merkle_tree: UncheckedAccount<'info>,
@@ -180,121 +275,1804 @@ pub struct WithdrawCNFT<'info> {
 
 #[derive(Accounts)]
 #[instruction(vault_id: u64, amount: u64)]
-pub struct WithdrawSPL<'info>{
+pub struct DepositSPL<'info>{
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.allows_token_program(&token_program.key()) @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
   /**
-   * Only the Owner can Widraw NFTs from this Vault
-   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   * Only the Owner or a Delegate can Deposit into this Vault
+   * Cannot deposit if the vault is frozen (currently being used in a Trade)
    */
   #[account(
-    constraint = (vault.owner.key() == payer.key()) && (!vault.is_frozen),
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_DEPOSIT) && (!vault.is_frozen),
     seeds=[
       b"vault",
-      payer.key().to_bytes().as_ref(),
+      vault.creator.to_bytes().as_ref(),
       vault_id.to_be_bytes().as_ref(),
     ],
-    bump,
+    bump = vault.bump,
   )]
   pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 
   #[account(mut)]
-  pub vault_ata: Account<'info, TokenAccount>,
-  #[account(mut)]
-  pub receiver_ata: Account<'info, TokenAccount>,
-  pub token_program: Program<'info, Token>,
+  pub payer_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+  #[account(
+    mut,
+    constraint = vault_ata.owner == vault.key()
+  )]
+  pub vault_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+  #[account(address = vault_ata.mint)]
+  pub mint: InterfaceAccount<'info, token_interface::Mint>,
+  pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
 #[instruction(vault_id: u64, amount: u64)]
-pub struct WithdrawSOL<'info>{
+pub struct DepositSOL<'info>{
+  #[account(mut)]
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
   /**
-   * Only the Owner can Widraw NFTs from this Vault
-   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   * Only the Owner or a Delegate can Deposit into this Vault
+   * Cannot deposit if the vault is frozen (currently being used in a Trade)
    */
   #[account(
-    constraint = (vault.owner.key() == payer.key()) && (!vault.is_frozen),
+    mut,
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_DEPOSIT) && (!vault.is_frozen),
     seeds=[
       b"vault",
-      payer.key().to_bytes().as_ref(),
+      vault.creator.to_bytes().as_ref(),
       vault_id.to_be_bytes().as_ref(),
     ],
-    bump,
+    bump = vault.bump,
   )]
   pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
 
-  #[account(mut)]
-  pub receiver: AccountInfo<'info>,
-  pub rent_sysvar: Sysvar<'info, Rent>
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 }
 
 #[derive(Accounts)]
-#[instruction(trade_id: u64)]
-pub struct CreateTrade<'info>{
+#[instruction(vault_id: u64)]
+pub struct DepositCore<'info> {
   #[account(mut)]
   pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.core @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
   pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Deposit into this Vault
+   * Cannot deposit if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_DEPOSIT) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
 
   #[account(
-    init,
-    payer=payer,
+    mut,
     seeds=[
-      b"trade",
-      payer.key().to_bytes().as_ref(),
-      trade_id.to_be_bytes().as_ref(),
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
     ],
-    bump,
-    space=8+TradeAccount::get_size()
+    bump = manifest.bump,
   )]
-  pub trade: Account<'info, TradeAccount>
-}
+  pub manifest: Account<'info, VaultManifest>,
 
-#[derive(Accounts)]
-pub struct JoinTrade<'info>{
-  pub payer: Signer<'info>,
-  #[account(mut)]
-  pub trade: Account<'info, TradeAccount>
+  /// CHECK: Core asset, validated by the Core program
+  #[account(
+    mut,
+    owner = mpl_core::ID,
+  )]
+  pub asset: UncheckedAccount<'info>,
+  /// CHECK: The asset's collection, required by the Core program when the asset belongs to one
+  pub collection: Option<UncheckedAccount<'info>>,
+  /// CHECK: Checked via ID
+  #[account(address = mpl_core::ID)]
+  pub core_program: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
-pub struct AttachVault<'info>{
-  pub payer: Signer<'info>,
-
+#[instruction(vault_id:u64)]
+pub struct WithdrawNFT<'info> {
+  /// Pays for receiver_ata when it's created
   #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw NFTs from this Vault
+   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
   pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
 
-  #[account(mut)]
-  pub trade: Account<'info, TradeAccount>
-}
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 
-#[derive(Accounts)]
-pub struct LockTrade<'info> {
-  pub payer: Signer<'info>,
+  #[account(
+    mut,
+    constraint = vault_ata.owner == vault.key() @ Errors::NotVaultTokenAccount,
+  )]
+  pub vault_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+  #[account(address = vault_ata.mint @ Errors::MintMismatch)]
+  pub mint: InterfaceAccount<'info, token_interface::Mint>,
+  /// CHECK: Can be anything, could be a PDA. Must own receiver_ata
+  pub receiver: UncheckedAccount<'info>,
+  /// CHECK: Created when associated_token_program is passed, mint and owner checked in the handler
   #[account(mut)]
-  pub trade: Account<'info, TradeAccount>
+  pub receiver_ata: UncheckedAccount<'info>,
+  pub token_program: Interface<'info, TokenInterface>,
+  /// Pass to create receiver_ata as the receiver's ATA if it doesn't exist yet
+  pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+  /// CHECK: The vault owner, receives the rent of a closed vault token account
+  #[account(mut, address = vault.owner)]
+  pub owner: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CancelTrade<'info> {
-  #[account(mut)]
+#[instruction(vault_id: u64, authorization_data: Option<AuthorizationDataLocal>, rules_acc_present: bool)]
+pub struct WithdrawPNFT<'info> {
   pub payer: Signer<'info>,
-  #[account(mut)]
-  pub trade: Account<'info, TradeAccount>,
-  #[account(mut)]
-  pub creator_vault: Option<Account<'info, Vault>>,
-  #[account(mut)]
-  pub acceptor_vault: Option<Account<'info, Vault>>,
-}
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw NFTs from this Vault
+   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
 
-#[derive(Accounts)]
-pub struct ConfirmTrade<'info> {
-  pub payer: Signer<'info>,
-  #[account(mut)]
-  pub trade: Account<'info, TradeAccount>,
-  #[account(mut)]
-  pub creator_vault: Account<'info, Vault>,
   #[account(mut)]
-  pub acceptor_vault: Account<'info, Vault>,
-}
\ No newline at end of file
+  pub vault_ata: Account<'info, TokenAccount>,
+  #[account(
+    mut, //will get initialized by mpl program
+    associated_token::mint = nft_mint,
+    associated_token::authority = receiver    
+  )]
+  pub receiver_ata: Account<'info, TokenAccount>,
+  /// CHECK: The owner of the receiver ATA, passed into transfer() call
+  pub receiver: UncheckedAccount<'info>,
+  pub nft_mint: Account<'info, Mint>,
+  #[account(
+    mut,
+    seeds=[
+        mpl_token_metadata::state::PREFIX.as_bytes(),
+        mpl_token_metadata::id().as_ref(),
+        nft_mint.key().as_ref(),
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump
+  )]
+  pub metadata: UncheckedAccount<'info>,
+  /// CHECK: Checked with seeds constraint
+  #[account(
+      seeds=[
+          mpl_token_metadata::state::PREFIX.as_bytes(),
+          mpl_token_metadata::id().as_ref(),
+          nft_mint.key().as_ref(),
+          mpl_token_metadata::state::EDITION.as_bytes(),
+      ],
+      seeds::program = mpl_token_metadata::id(),
+      bump
+  )]
+  pub edition: UncheckedAccount<'info>,
+  /// CHECK: seeds below
+  #[account(mut,
+    seeds=[
+        mpl_token_metadata::state::PREFIX.as_bytes(),
+        mpl_token_metadata::id().as_ref(),
+        nft_mint.key().as_ref(),
+        mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
+        vault_ata.key().as_ref()
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump
+  )]
+  pub owner_token_record: UncheckedAccount<'info>,
+  /// CHECK: seeds below
+  #[account(mut,
+          seeds=[
+              mpl_token_metadata::state::PREFIX.as_bytes(),
+              mpl_token_metadata::id().as_ref(),
+              nft_mint.key().as_ref(),
+              mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
+              receiver_ata.key().as_ref()
+          ],
+          seeds::program = mpl_token_metadata::id(),
+          bump
+      )]
+  pub receiver_token_record: UncheckedAccount<'info>,
+  /// CHECK: Auth Rules Program
+  #[account(address = mpl_token_auth_rules::id())]
+  pub auth_rules_program: UncheckedAccount<'info>,
+  /// CHECK: Deserialization errors, so we just manually check the ID
+  #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+  pub sysvar_instructions: UncheckedAccount<'info>,
+  pub token_program: Program<'info, Token>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64, root: [u8; 32], data_hash: [u8; 32], creator_hash: [u8; 32], nonce: u64, index: u32)]
+pub struct WithdrawCNFT<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw NFTs from this Vault
+   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  /// CHECK: Can be anything, could be a PDA
+  pub receiver: UncheckedAccount<'info>,
+  #[account(
+    seeds = [merkle_tree.key().as_ref()],
+    bump, 
+    seeds::program = bubblegum_program.key()
+  )]
+  /// CHECK: This account is neither written to nor read from.
+  pub tree_authority: Account<'info, TreeConfig>,
+  /// CHECK: This account is neither written to nor read from.
+  pub new_leaf_owner: UncheckedAccount<'info>, // receiver
+  #[account(mut)]
+  /// CHECK: This account is modified in the downstream program
+  pub merkle_tree: UncheckedAccount<'info>,
+  pub log_wrapper: Program<'info, Noop>,
+  pub compression_program: Program<'info, SplAccountCompression>,
+  /// CHECK: Checked via ID
+  #[account(address = mpl_bubblegum::id())]
+  pub bubblegum_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64, amount: u64)]
+pub struct WithdrawSPL<'info>{
+  /// Pays for receiver_ata when it's created
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw NFTs from this Vault
+   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  #[account(
+    mut,
+    constraint = vault_ata.owner == vault.key() @ Errors::NotVaultTokenAccount,
+  )]
+  pub vault_ata: InterfaceAccount<'info, token_interface::TokenAccount>,
+  #[account(address = vault_ata.mint @ Errors::MintMismatch)]
+  pub mint: InterfaceAccount<'info, token_interface::Mint>,
+  /// CHECK: Can be anything, could be a PDA. Must own receiver_ata
+  pub receiver: UncheckedAccount<'info>,
+  /// CHECK: Created when associated_token_program is passed, mint and owner checked in the handler
+  #[account(mut)]
+  pub receiver_ata: UncheckedAccount<'info>,
+  pub token_program: Interface<'info, TokenInterface>,
+  /// Pass to create receiver_ata as the receiver's ATA if it doesn't exist yet
+  pub associated_token_program: Option<Program<'info, AssociatedToken>>,
+  /// CHECK: The vault owner, receives the rent of a closed vault token account
+  #[account(mut, address = vault.owner)]
+  pub owner: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64, amount: u64)]
+pub struct WithdrawSOL<'info>{
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw NFTs from this Vault
+   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    mut,
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  #[account(mut)]
+  pub receiver: AccountInfo<'info>,
+  pub rent_sysvar: Sysvar<'info, Rent>
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64)]
+pub struct WithdrawCore<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw from this Vault
+   * Cannot withdraw if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  /// CHECK: Can be anything, could be a PDA
+  pub receiver: UncheckedAccount<'info>,
+  /// CHECK: Core asset, validated by the Core program
+  #[account(
+    mut,
+    owner = mpl_core::ID,
+  )]
+  pub asset: UncheckedAccount<'info>,
+  /// CHECK: The asset's collection, required by the Core program when the asset belongs to one
+  pub collection: Option<UncheckedAccount<'info>>,
+  /// CHECK: Checked via ID
+  #[account(address = mpl_core::ID)]
+  pub core_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64)]
+pub struct WithdrawTokenBatch<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /**
+   * Only the Owner or a Delegate can Widraw from this Vault
+   * Cannot withdraw if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+  /// CHECK: Can be anything, could be a PDA. Must own every receiver_ata in the batch
+  pub receiver: UncheckedAccount<'info>,
+  /// Owns every vault token account in the batch
+  pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(vault_id: u64)]
+pub struct WithdrawPNFTBatch<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  /**
+   * Only the Owner or a Delegate can Widraw NFTs from this Vault
+   * Cannot withdraw nfts if the vault is frozen (currently being used in a Trade)
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_WITHDRAW) && (!vault.is_frozen),
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+  /// CHECK: Can be anything, could be a PDA. Owns every receiver ATA in the batch
+  pub receiver: UncheckedAccount<'info>,
+  /// CHECK: Rule set shared by the batch's pNFTs, checked against each pNFT's metadata
+  pub auth_rules: Option<UncheckedAccount<'info>>,
+  /// CHECK: Auth Rules Program
+  #[account(address = mpl_token_auth_rules::id())]
+  pub auth_rules_program: UncheckedAccount<'info>,
+  /// CHECK: Deserialization errors, so we just manually check the ID
+  #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+  pub sysvar_instructions: UncheckedAccount<'info>,
+  pub token_program: Program<'info, Token>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct CloseVault<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /**
+   * Only the Owner or a Delegate can close this Vault, and only while it isn't frozen
+   * All of its lamports, deposited SOL included, go to the receiver, which must be the Owner when a Delegate closes
+   */
+  #[account(
+    mut,
+    close = receiver,
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_CLOSE) @ Errors::VaultNotOwned,
+    constraint = !vault.is_frozen @ Errors::VaultAlreadyInTrade,
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault.vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    close = receiver,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  /// CHECK: Can be anything when the Owner closes, receives the vault's lamports
+  #[account(
+    mut,
+    constraint = (vault.owner == payer.key() || receiver.key() == vault.owner) @ Errors::DelegateReceiverNotOwner,
+  )]
+  pub receiver: UncheckedAccount<'info>,
+  /// Needed for whichever programs own the token accounts passed in remaining accounts
+  pub token_program: Option<Program<'info, Token>>,
+  pub token_2022_program: Option<Program<'info, Token2022>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseVaultTokenAccounts<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /**
+   * Only the Owner or a Delegate can close this Vault's token accounts
+   * Only empty ones can be closed, so it's fine while the vault is frozen
+   */
+  #[account(
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_CLOSE) @ Errors::VaultNotOwned,
+    seeds=[
+      b"vault",
+      vault.creator.to_bytes().as_ref(),
+      vault.vault_id.to_be_bytes().as_ref(),
+    ],
+    bump = vault.bump,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+  /// CHECK: The vault owner, receives the rent of the closed token accounts
+  #[account(mut, address = vault.owner)]
+  pub owner: UncheckedAccount<'info>,
+  /// Needed for whichever programs own the token accounts passed in remaining accounts
+  pub token_program: Option<Program<'info, Token>>,
+  pub token_2022_program: Option<Program<'info, Token2022>>,
+}
+
+#[derive(Accounts)]
+pub struct GrantDelegate<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+
+  /// Only the Owner can grant rights on their Vault
+  #[account(
+    constraint = vault.owner == payer.key() @ Errors::VaultNotOwned,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  /// CHECK: Any wallet, could be a PDA
+  pub delegate: UncheckedAccount<'info>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"delegate",
+      vault.key().to_bytes().as_ref(),
+      delegate.key().to_bytes().as_ref(),
+    ],
+    bump,
+    space=8+VaultDelegate::get_size()
+  )]
+  pub vault_delegate: Account<'info, VaultDelegate>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateDelegate<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+
+  #[account(
+    constraint = vault.owner == payer.key() @ Errors::VaultNotOwned,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  /// A grant from a previous owner is re-issued in the current owner's name
+  #[account(
+    mut,
+    seeds=[
+      b"delegate",
+      vault.key().to_bytes().as_ref(),
+      vault_delegate.delegate.to_bytes().as_ref(),
+    ],
+    bump = vault_delegate.bump,
+  )]
+  pub vault_delegate: Account<'info, VaultDelegate>,
+}
+
+/// No pause check: revoking must work while paused, so a compromised delegate can always be cut off
+#[derive(Accounts)]
+pub struct RevokeDelegate<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+
+  pub vault: Account<'info, Vault>,
+
+  /**
+   * The current Owner, the Owner who granted it or the Delegate itself can revoke
+   * Rent goes back to whoever revokes
+   */
+  #[account(
+    mut,
+    close = payer,
+    constraint = (
+      payer.key() == vault.owner
+      || payer.key() == vault_delegate.owner
+      || payer.key() == vault_delegate.delegate
+    ) @ Errors::NotVaultDelegate,
+    seeds=[
+      b"delegate",
+      vault.key().to_bytes().as_ref(),
+      vault_delegate.delegate.to_bytes().as_ref(),
+    ],
+    bump = vault_delegate.bump,
+  )]
+  pub vault_delegate: Account<'info, VaultDelegate>,
+}
+
+#[derive(Accounts)]
+#[instruction(trade_id: u64, expires_at: Option<i64>, allowed_acceptor: Option<Pubkey>, payment: Option<TradePayment>)]
+pub struct CreateTrade<'info>{
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"trade",
+      payer.key().to_bytes().as_ref(),
+      trade_id.to_be_bytes().as_ref(),
+    ],
+    bump,
+    space=8+TradeAccount::get_size()
+  )]
+  pub trade: Account<'info, TradeAccount>
+}
+
+#[derive(Accounts)]
+pub struct JoinTrade<'info>{
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, TradeAccount>
+}
+
+#[derive(Accounts)]
+pub struct AttachVault<'info>{
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+
+  /**
+   * Only the Owner or a Delegate can attach their Vault, always to the Owner's own side
+   * A Vault can only be frozen into one trade at a time, re-attaching to the same trade is fine
+   */
+  #[account(
+    mut,
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_TRADE) @ Errors::VaultNotOwned,
+    constraint = (!vault.is_frozen || vault.active_trade == Some(trade.key())) @ Errors::VaultAlreadyInTrade,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, TradeAccount>
+}
+
+#[derive(Accounts)]
+pub struct DetachVault<'info>{
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+
+  /// Only the Owner or a Delegate can detach their Vault, always from the Owner's own side
+  #[account(
+    mut,
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_TRADE) @ Errors::VaultNotOwned,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, TradeAccount>
+}
+
+/**
+ * Remaining accounts: the manifest of every vault the payer is going to receive, in attach order.
+ * Their hash is stored on the trade so confirm_trade can check nothing moved since.
+ * In a payment trade the acceptor's lock escrows the payment, the token accounts are only needed for SPL payments.
+ */
+#[derive(Accounts)]
+pub struct LockTrade<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, TradeAccount>,
+  /// With vault_delegate, lets a Delegate lock for the Owner of this vault, which must be attached on the Owner's side
+  pub vault: Option<Account<'info, Vault>>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+  #[account(mut)]
+  pub payer_token_account: Option<Account<'info, TokenAccount>>,
+  /// Token account owned by the trade account that holds the SPL payment until settlement
+  #[account(mut)]
+  pub payment_escrow: Option<Account<'info, TokenAccount>>,
+  pub token_program: Option<Program<'info, Token>>,
+}
+
+/**
+ * Remaining accounts: the vaults to unfreeze (writable), in attach order.
+ * Creator cancelling: creator vaults then acceptor vaults. Acceptor leaving: acceptor vaults.
+ * An escrowed payment is refunded to the acceptor.
+ */
+#[derive(Accounts)]
+pub struct CancelTrade<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, TradeAccount>,
+  /// CHECK: Receives the payment refund, checked against the trade acceptor
+  #[account(mut)]
+  pub acceptor: Option<UncheckedAccount<'info>>,
+  #[account(mut)]
+  pub payment_escrow: Option<Account<'info, TokenAccount>>,
+  #[account(mut)]
+  pub acceptor_token_account: Option<Account<'info, TokenAccount>>,
+  pub token_program: Option<Program<'info, Token>>,
+}
+
+/**
+ * Remaining accounts, each group in attach order:
+ * creator vaults, acceptor vaults (all writable), creator vault manifests, acceptor vault manifests
+ */
+#[derive(Accounts)]
+pub struct ConfirmTrade<'info> {
+  pub payer: Signer<'info>,
+  /**
+   * Settled trades are closed, so lock/attach/cancel can't be replayed against them
+   * Rent goes back to the trade creator
+   */
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+    close = creator,
+  )]
+  pub trade: Account<'info, TradeAccount>,
+  /// CHECK: Receives the trade account's rent and any payment, checked against the trade creator
+  #[account(
+    mut,
+    address = trade.creator_key,
+  )]
+  pub creator: UncheckedAccount<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /// CHECK: Protocol fee receiver, or its token account for SPL payments, checked against the config on payout
+  #[account(mut)]
+  pub fee_receiver: UncheckedAccount<'info>,
+  #[account(mut)]
+  pub payment_escrow: Option<Account<'info, TokenAccount>>,
+  #[account(mut)]
+  pub creator_token_account: Option<Account<'info, TokenAccount>>,
+  pub token_program: Option<Program<'info, Token>>,
+}
+
+/**
+ * Remaining accounts: creator vaults then acceptor vaults (writable), in attach order
+ * An escrowed payment is refunded to the acceptor.
+ */
+#[derive(Accounts)]
+pub struct ExpireTrade<'info> {
+  /// Anyone can crank an expired trade
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    seeds=[
+      b"trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+    close = creator,
+  )]
+  pub trade: Account<'info, TradeAccount>,
+  /// CHECK: Only receives the trade account's rent, checked against the trade creator
+  #[account(
+    mut,
+    address = trade.creator_key,
+  )]
+  pub creator: UncheckedAccount<'info>,
+  /// CHECK: Receives the payment refund, checked against the trade acceptor
+  #[account(mut)]
+  pub acceptor: Option<UncheckedAccount<'info>>,
+  #[account(mut)]
+  pub payment_escrow: Option<Account<'info, TokenAccount>>,
+  #[account(mut)]
+  pub acceptor_token_account: Option<Account<'info, TokenAccount>>,
+  pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+#[instruction(trade_id: u64, participants: Vec<Pubkey>, routes: Vec<u8>, expires_at: Option<i64>)]
+pub struct CreateMultiTrade<'info>{
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"multi_trade",
+      payer.key().to_bytes().as_ref(),
+      trade_id.to_be_bytes().as_ref(),
+    ],
+    bump,
+    space=8+MultiTradeAccount::get_size()
+  )]
+  pub trade: Account<'info, MultiTradeAccount>
+}
+
+#[derive(Accounts)]
+pub struct AttachMultiVault<'info>{
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+
+  /**
+   * Only the Owner or a Delegate can attach their Vault, always to the Owner's own side
+   * A Vault can only be frozen into one trade at a time, re-attaching to the same trade is fine
+   */
+  #[account(
+    mut,
+    constraint = vault.can_manage(vault.key(), payer.key(), vault_delegate.as_deref(), DELEGATE_TRADE) @ Errors::VaultNotOwned,
+    constraint = (!vault.is_frozen || vault.active_trade == Some(trade.key())) @ Errors::VaultAlreadyInTrade,
+  )]
+  pub vault: Account<'info, Vault>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+
+  /// The vault this participant previously had attached, unfrozen when it gets swapped out
+  #[account(mut)]
+  pub replaced_vault: Option<Account<'info, Vault>>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"multi_trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, MultiTradeAccount>
+}
+
+/**
+ * Remaining accounts: the manifest of every participant's vault, in participant order
+ */
+#[derive(Accounts)]
+pub struct LockMultiTrade<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    seeds=[
+      b"multi_trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, MultiTradeAccount>,
+  /// With vault_delegate, lets a Delegate lock for the Owner of this vault, which must be attached on the Owner's side
+  pub vault: Option<Account<'info, Vault>>,
+  /// Grant letting a wallet other than the owner act on the vault
+  pub vault_delegate: Option<Account<'info, VaultDelegate>>,
+}
+
+/**
+ * Remaining accounts: every participant's vault (writable), then every vault's manifest,
+ * both in participant order
+ */
+#[derive(Accounts)]
+pub struct ConfirmMultiTrade<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    close = creator,
+    seeds=[
+      b"multi_trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, MultiTradeAccount>,
+  /// CHECK: Only receives the trade account's rent, checked against the trade creator
+  #[account(
+    mut,
+    address = trade.creator_key,
+  )]
+  pub creator: UncheckedAccount<'info>,
+}
+
+/**
+ * Remaining accounts: every attached vault (writable), in participant order
+ */
+#[derive(Accounts)]
+pub struct CancelMultiTrade<'info> {
+  /// Any participant, or anyone once the trade has expired
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    close = creator,
+    seeds=[
+      b"multi_trade",
+      trade.creator_key.to_bytes().as_ref(),
+      trade.trade_id.to_be_bytes().as_ref(),
+    ],
+    bump = trade.bump,
+  )]
+  pub trade: Account<'info, MultiTradeAccount>,
+  /// CHECK: Only receives the trade account's rent, checked against the trade creator
+  #[account(
+    mut,
+    address = trade.creator_key,
+  )]
+  pub creator: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ListVault<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+
+  /**
+   * Only the Owner can list their Vault
+   * A listed Vault is frozen just like one attached to a trade
+   */
+  #[account(
+    mut,
+    constraint = vault.owner == payer.key() @ Errors::VaultNotOwned,
+    constraint = !vault.is_frozen @ Errors::VaultAlreadyInTrade,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"listing",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump,
+    space=8+Listing::get_size()
+  )]
+  pub listing: Account<'info, Listing>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateListing<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    constraint = listing.seller == payer.key() @ Errors::NotListingSeller,
+  )]
+  pub listing: Account<'info, Listing>,
+}
+
+#[derive(Accounts)]
+pub struct DelistVault<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    address = listing.vault,
+  )]
+  pub vault: Account<'info, Vault>,
+  #[account(
+    mut,
+    close = payer,
+    constraint = listing.seller == payer.key() @ Errors::NotListingSeller,
+    seeds=[
+      b"listing",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = listing.bump,
+  )]
+  pub listing: Account<'info, Listing>,
+}
+
+/**
+ * Token accounts are only needed when the listing is priced in an SPL mint
+ */
+#[derive(Accounts)]
+pub struct BuyVault<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  pub system_program: Program<'info, System>,
+  #[account(
+    mut,
+    address = listing.vault,
+  )]
+  pub vault: Account<'info, Vault>,
+  #[account(
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+  /// Closed back to the seller once the sale goes through
+  #[account(
+    mut,
+    close = seller,
+    seeds=[
+      b"listing",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = listing.bump,
+  )]
+  pub listing: Account<'info, Listing>,
+  /// CHECK: Receives the payment and the listing's rent, checked against the listing seller
+  #[account(
+    mut,
+    address = listing.seller,
+  )]
+  pub seller: UncheckedAccount<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /// CHECK: Protocol fee receiver, or its token account for SPL payments, checked against the config on payout
+  #[account(mut)]
+  pub fee_receiver: UncheckedAccount<'info>,
+  #[account(mut)]
+  pub payer_token_account: Option<Account<'info, TokenAccount>>,
+  #[account(mut)]
+  pub seller_token_account: Option<Account<'info, TokenAccount>>,
+  pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct CreateAuction<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+
+  /**
+   * Only the Owner can auction their Vault
+   * The Vault stays frozen for the life of the auction
+   */
+  #[account(
+    mut,
+    constraint = vault.owner == payer.key() @ Errors::VaultNotOwned,
+    constraint = !vault.is_frozen @ Errors::VaultAlreadyInTrade,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"auction",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump,
+    space=8+Auction::get_size()
+  )]
+  pub auction: Account<'info, Auction>,
+}
+
+#[derive(Accounts)]
+pub struct PlaceBid<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+  #[account(
+    mut,
+    seeds=[
+      b"auction",
+      auction.vault.to_bytes().as_ref(),
+    ],
+    bump = auction.bump,
+  )]
+  pub auction: Account<'info, Auction>,
+  /// CHECK: Gets their bid refunded, checked against the current highest bidder
+  #[account(mut)]
+  pub previous_bidder: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct SettleAuction<'info> {
+  /// Anyone can settle an auction once it has ended
+  pub payer: Signer<'info>,
+  #[account(
+    mut,
+    address = auction.vault,
+  )]
+  pub vault: Account<'info, Vault>,
+  #[account(
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+  /// Closed back to the seller once settled
+  #[account(
+    mut,
+    close = seller,
+    seeds=[
+      b"auction",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = auction.bump,
+  )]
+  pub auction: Account<'info, Auction>,
+  /// CHECK: Receives the winning bid and the auction's rent, checked against the auction seller
+  #[account(
+    mut,
+    address = auction.seller,
+  )]
+  pub seller: UncheckedAccount<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /// CHECK: Protocol fee receiver, or its token account for SPL payments, checked against the config on payout
+  #[account(mut)]
+  pub fee_receiver: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAuction<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  #[account(
+    mut,
+    address = auction.vault,
+  )]
+  pub vault: Account<'info, Vault>,
+  #[account(
+    mut,
+    close = payer,
+    constraint = auction.seller == payer.key() @ Errors::NotAuctionSeller,
+    seeds=[
+      b"auction",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = auction.bump,
+  )]
+  pub auction: Account<'info, Auction>,
+}
+
+#[derive(Accounts)]
+#[instruction(offer_id: u64)]
+pub struct MakeCollectionOffer<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  pub system_program: Program<'info, System>,
+
+  /// Vault the filled NFT gets deposited into
+  /// Owner only, no delegates: filling requires the buyer who escrowed the price to own the vault
+  #[account(
+    constraint = vault.owner == payer.key() @ Errors::VaultNotOwned,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  #[account(
+    init,
+    payer=payer,
+    seeds=[
+      b"offer",
+      payer.key().to_bytes().as_ref(),
+      offer_id.to_be_bytes().as_ref(),
+    ],
+    bump,
+    space=8+Offer::get_size()
+  )]
+  pub offer: Account<'info, Offer>,
+}
+
+#[derive(Accounts)]
+pub struct CancelCollectionOffer<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+  )]
+  pub config: Account<'info, Config>,
+  /// Escrowed price and rent go back to the buyer
+  #[account(
+    mut,
+    close = payer,
+    constraint = offer.buyer == payer.key() @ Errors::NotOfferBuyer,
+    seeds=[
+      b"offer",
+      offer.buyer.to_bytes().as_ref(),
+      offer.offer_id.to_be_bytes().as_ref(),
+    ],
+    bump = offer.bump,
+  )]
+  pub offer: Account<'info, Offer>,
+}
+
+#[derive(Accounts)]
+#[instruction(authorization_data: Option<AuthorizationDataLocal>, rules_acc_present: bool)]
+pub struct AcceptCollectionOffer<'info> {
+  /// Holder filling the offer
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  pub system_program: Program<'info, System>,
+
+  /**
+   * The buyer must still own the Vault
+   * Cannot fill into a frozen vault, it would change the terms of its trade
+   */
+  #[account(
+    address = offer.vault,
+    constraint = vault.owner == offer.buyer @ Errors::VaultNotOwned,
+    constraint = !vault.is_frozen @ Errors::VaultAlreadyInTrade,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  /// Filled offers are closed back to the buyer
+  #[account(
+    mut,
+    close = buyer,
+    seeds=[
+      b"offer",
+      offer.buyer.to_bytes().as_ref(),
+      offer.offer_id.to_be_bytes().as_ref(),
+    ],
+    bump = offer.bump,
+  )]
+  pub offer: Account<'info, Offer>,
+  /// CHECK: Receives the offer's rent, checked against the offer buyer
+  #[account(
+    mut,
+    address = offer.buyer,
+  )]
+  pub buyer: UncheckedAccount<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.token @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
+  /// CHECK: Protocol fee receiver, or its token account for SPL payments, checked against the config on payout
+  #[account(mut)]
+  pub fee_receiver: UncheckedAccount<'info>,
+
+  #[account(
+    mut,
+    constraint = payer_ata.mint == nft_mint.key() @ Errors::CollectionMismatch,
+  )]
+  pub payer_ata: Account<'info, TokenAccount>,
+  /// CHECK: Vault's ATA for the mint, created here or by the mpl program
+  #[account(
+    mut,
+    address = anchor_spl::associated_token::get_associated_token_address(&vault.key(), &nft_mint.key())
+  )]
+  pub vault_ata: UncheckedAccount<'info>,
+  #[account(
+    constraint = nft_mint.supply == 1 && nft_mint.decimals == 0 @ Errors::CollectionMismatch,
+  )]
+  pub nft_mint: Account<'info, Mint>,
+  /// CHECK: Checked with seeds constraint
+  #[account(
+    mut,
+    seeds=[
+        mpl_token_metadata::state::PREFIX.as_bytes(),
+        mpl_token_metadata::id().as_ref(),
+        nft_mint.key().as_ref(),
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump
+  )]
+  pub metadata: UncheckedAccount<'info>,
+  /// CHECK: Checked with seeds constraint
+  #[account(
+      seeds=[
+          mpl_token_metadata::state::PREFIX.as_bytes(),
+          mpl_token_metadata::id().as_ref(),
+          nft_mint.key().as_ref(),
+          mpl_token_metadata::state::EDITION.as_bytes(),
+      ],
+      seeds::program = mpl_token_metadata::id(),
+      bump
+  )]
+  pub edition: UncheckedAccount<'info>,
+  /// CHECK: seeds below
+  #[account(mut,
+    seeds=[
+        mpl_token_metadata::state::PREFIX.as_bytes(),
+        mpl_token_metadata::id().as_ref(),
+        nft_mint.key().as_ref(),
+        mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
+        payer_ata.key().as_ref()
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump
+  )]
+  pub owner_token_record: UncheckedAccount<'info>,
+  /// CHECK: seeds below
+  #[account(mut,
+    seeds=[
+        mpl_token_metadata::state::PREFIX.as_bytes(),
+        mpl_token_metadata::id().as_ref(),
+        nft_mint.key().as_ref(),
+        mpl_token_metadata::state::TOKEN_RECORD_SEED.as_bytes(),
+        vault_ata.key().as_ref()
+    ],
+    seeds::program = mpl_token_metadata::id(),
+    bump
+  )]
+  pub vault_token_record: UncheckedAccount<'info>,
+  /// CHECK: Auth Rules Program
+  #[account(address = mpl_token_auth_rules::id())]
+  pub auth_rules_program: UncheckedAccount<'info>,
+  /// CHECK: Deserialization errors, so we just manually check the ID
+  #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+  pub sysvar_instructions: UncheckedAccount<'info>,
+  pub token_program: Program<'info, Token>,
+  pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+#[instruction(root: [u8; 32], metadata: MetadataArgs, nonce: u64, index: u32)]
+pub struct AcceptCollectionOfferCNFT<'info> {
+  /// Holder filling the offer, must be the current leaf owner
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  pub system_program: Program<'info, System>,
+
+  /**
+   * The buyer must still own the Vault
+   * Cannot fill into a frozen vault, it would change the terms of its trade
+   */
+  #[account(
+    address = offer.vault,
+    constraint = vault.owner == offer.buyer @ Errors::VaultNotOwned,
+    constraint = !vault.is_frozen @ Errors::VaultAlreadyInTrade,
+  )]
+  pub vault: Account<'info, Vault>,
+
+  #[account(
+    mut,
+    seeds=[
+      b"manifest",
+      vault.key().to_bytes().as_ref(),
+    ],
+    bump = manifest.bump,
+  )]
+  pub manifest: Account<'info, VaultManifest>,
+
+  /// Filled offers are closed back to the buyer
+  #[account(
+    mut,
+    close = buyer,
+    seeds=[
+      b"offer",
+      offer.buyer.to_bytes().as_ref(),
+      offer.offer_id.to_be_bytes().as_ref(),
+    ],
+    bump = offer.bump,
+  )]
+  pub offer: Account<'info, Offer>,
+  /// CHECK: Receives the offer's rent, checked against the offer buyer
+  #[account(
+    mut,
+    address = offer.buyer,
+  )]
+  pub buyer: UncheckedAccount<'info>,
+  #[account(
+    seeds=[b"config"],
+    bump = config.bump,
+    constraint = !config.paused @ Errors::ProgramPaused,
+    constraint = config.asset_programs.bubblegum @ Errors::AssetProgramDisabled,
+  )]
+  pub config: Account<'info, Config>,
+  /// CHECK: Protocol fee receiver, or its token account for SPL payments, checked against the config on payout
+  #[account(mut)]
+  pub fee_receiver: UncheckedAccount<'info>,
+
+  #[account(
+    seeds = [merkle_tree.key().as_ref()],
+    bump, 
+    seeds::program = bubblegum_program.key()
+  )]
+  /// CHECK: This account is neither written to nor read from.
+  pub tree_authority: Account<'info, TreeConfig>,
+  #[account(mut)]
+  /// CHECK: This account is modified in the downstream program
+  pub merkle_tree: UncheckedAccount<'info>,
+  pub log_wrapper: Program<'info, Noop>,
+  pub compression_program: Program<'info, SplAccountCompression>,
+  /// CHECK: Checked via ID
+  #[account(address = mpl_bubblegum::id())]
+  pub bubblegum_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+  #[account(mut)]
+  pub payer: Signer<'info>,
+  pub system_program: Program<'info, System>,
+  #[account(
+    init,
+    payer=payer,
+    seeds=[b"config"],
+    bump,
+    space=8+Config::get_size()
+  )]
+  pub config: Account<'info, Config>,
+  /// Only the upgrade authority can create the config
+  #[account(
+    constraint = program.programdata_address()? == Some(program_data.key()),
+  )]
+  pub program: Program<'info, Caravan>,
+  #[account(
+    constraint = program_data.upgrade_authority_address == Some(payer.key()) @ Errors::NotUpgradeAuthority,
+  )]
+  pub program_data: Account<'info, ProgramData>,
+}
+
+/// Also used to pause and unpause, which must work while paused
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+  pub payer: Signer<'info>,
+  #[account(
+    mut,
+    constraint = config.admin == payer.key() @ Errors::NotConfigAdmin,
+    seeds=[b"config"],
+    bump = config.bump,
+  )]
+  pub config: Account<'info, Config>,
+}
@@ -25,4 +25,172 @@ pub enum Errors {
 
     #[msg("Cancel trade error")]
     CancelTradeError,
+
+    #[msg("Vault manifest has no room for another asset")]
+    ManifestFull,
+
+    #[msg("Vault manifest amount overflow")]
+    ManifestOverflow,
+
+    #[msg("Vault contents changed after the trade was locked")]
+    TradeTermsChanged,
+
+    #[msg("Vaults can only be attached before both sides have locked")]
+    TradeNotAttachable,
+
+    #[msg("Trade can only be locked once both vaults are attached")]
+    TradeNotLockable,
+
+    #[msg("Trade can only be confirmed once both sides have locked")]
+    TradeNotConfirmable,
+
+    #[msg("Only the vault owner can do this")]
+    VaultNotOwned,
+
+    #[msg("Vault is already frozen by another trade, listing or auction")]
+    VaultAlreadyInTrade,
+
+    #[msg("Vault isn't frozen by this trade, listing or auction")]
+    VaultTradeMismatch,
+
+    #[msg("Trade expiry must be in the future")]
+    InvalidExpiry,
+
+    #[msg("Trade has expired")]
+    TradeExpired,
+
+    #[msg("Trade hasn't expired yet")]
+    TradeNotExpired,
+
+    #[msg("Trade is restricted to a different acceptor")]
+    AcceptorNotAllowed,
+
+    #[msg("Multi-party trades need between 3 and 5 distinct participants including the creator")]
+    InvalidParticipants,
+
+    #[msg("Routing table must send every vault to another participant")]
+    InvalidRoutes,
+
+    #[msg("Signer isn't a participant in this trade")]
+    NotAParticipant,
+
+    #[msg("Remaining accounts don't match the trade's vaults")]
+    TradeAccountsMismatch,
+
+    #[msg("This side of the trade already has the maximum number of vaults attached")]
+    TooManyVaults,
+
+    #[msg("Payment amount must be greater than zero")]
+    InvalidPayment,
+
+    #[msg("The acceptor pays instead of attaching vaults in a payment trade")]
+    PaymentTradeVault,
+
+    #[msg("Token accounts for the SPL payment are missing")]
+    PaymentAccountsMissing,
+
+    #[msg("Payment accounts don't match the trade's payment")]
+    PaymentAccountsMismatch,
+
+    #[msg("Price must be greater than zero")]
+    InvalidPrice,
+
+    #[msg("Only the seller can do this")]
+    NotListingSeller,
+
+    #[msg("Listing price is above what the buyer agreed to pay")]
+    ListingPriceChanged,
+
+    #[msg("Auction needs a future end time and a non-zero minimum increment")]
+    InvalidAuction,
+
+    #[msg("Only the seller can do this")]
+    NotAuctionSeller,
+
+    #[msg("Auction has already ended")]
+    AuctionEnded,
+
+    #[msg("Auction hasn't ended yet")]
+    AuctionNotEnded,
+
+    #[msg("Bid is below the reserve price or minimum increment")]
+    BidTooLow,
+
+    #[msg("Auction already has bids")]
+    AuctionHasBids,
+
+    #[msg("Only the buyer can do this")]
+    NotOfferBuyer,
+
+    #[msg("Asset isn't an NFT from the offer's verified collection")]
+    CollectionMismatch,
+
+    #[msg("Fees can't exceed 10000 basis points")]
+    InvalidFee,
+
+    #[msg("Only the program upgrade authority can do this")]
+    NotUpgradeAuthority,
+
+    #[msg("Only the config admin can do this")]
+    NotConfigAdmin,
+
+    #[msg("Fee receiver account doesn't match the config")]
+    FeeAccountsMismatch,
+
+    #[msg("Royalty accounts don't match the NFT metadata or vault contents")]
+    RoyaltyAccountsMismatch,
+
+    #[msg("Royalties must be paid for every NFT in the vault")]
+    RoyaltiesMissing,
+
+    #[msg("Fees and royalties exceed the payment")]
+    FeesExceedPayment,
+
+    #[msg("Program is paused")]
+    ProgramPaused,
+
+    #[msg("Asset program is disabled")]
+    AssetProgramDisabled,
+
+    #[msg("Couldn't compute the Token-2022 transfer fee")]
+    TransferFeeError,
+
+    #[msg("Transfer hook accounts are missing or don't match the mint's hook")]
+    TransferHookAccountsMismatch,
+
+    #[msg("Vault still holds assets")]
+    VaultNotEmpty,
+
+    #[msg("Token account isn't owned by the vault")]
+    NotVaultTokenAccount,
+
+    #[msg("Batch accounts don't form complete groups")]
+    MalformedBatch,
+
+    #[msg("Batch is empty or over the per-call limit")]
+    InvalidBatchSize,
+
+    #[msg("Token accounts don't share a mint")]
+    MintMismatch,
+
+    #[msg("Receiver token account isn't owned by the receiver")]
+    ReceiverAtaMismatch,
+
+    #[msg("Delegate permissions are empty or unknown")]
+    InvalidDelegatePermissions,
+
+    #[msg("Signer can't revoke this delegate")]
+    NotVaultDelegate,
+
+    #[msg("Trade already has an acceptor")]
+    TradeAlreadyJoined,
+
+    #[msg("Listing payment mint isn't the one the buyer agreed to pay in")]
+    ListingPaymentMintChanged,
+
+    #[msg("Couldn't build the pNFT transfer")]
+    PNftTransferError,
+
+    #[msg("A delegate can only send the vault's lamports to the owner")]
+    DelegateReceiverNotOwner,
 }
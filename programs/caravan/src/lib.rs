@@ -1,27 +1,56 @@
-use anchor_lang::prelude::*;
-use anchor_spl::token::{transfer as transferSPL, Mint, Transfer as TransferNFT};
+use anchor_lang::solana_program::{
+    instruction::Instruction,
+    program::{invoke, invoke_signed},
+    system_instruction::transfer,
+};
+use anchor_lang::{prelude::*, solana_program::hash::hashv};
+use anchor_spl::associated_token::{create_idempotent, AssociatedToken, Create};
+use anchor_spl::token::{
+    close_account, transfer as transferSPL, CloseAccount, Mint, Token, TokenAccount,
+    Transfer as TransferNFT,
+};
+use anchor_spl::token_2022::Token2022;
+use anchor_spl::token_interface::{
+    self, transfer_checked, Mint as InterfaceMint, TokenInterface, TransferChecked,
+};
+use mpl_bubblegum::{
+    hash_creators, hash_metadata, state::metaplex_adapter::MetadataArgs, utils::get_asset_id,
+};
 use mpl_token_metadata::{
     instruction::{builders::TransferBuilder, InstructionBuilder, TransferArgs},
     processor::AuthorizationData,
-    state::{Metadata, ProgrammableConfig::V1, TokenMetadataAccount},
+    state::{Metadata, ProgrammableConfig::V1, TokenMetadataAccount, TokenStandard},
 };
+use spl_token_2022::{
+    extension::{
+        transfer_fee::TransferFeeConfig, transfer_hook, BaseStateWithExtensions,
+        StateWithExtensions,
+    },
+    state::Mint as MintState,
+};
+use spl_transfer_hook_interface::onchain::add_cpi_accounts_for_execute;
 
 pub mod account;
 pub mod context;
 pub mod error;
+pub mod metaplex_core;
 pub mod pnft;
 
 use crate::account::*;
+use crate::metaplex_core::*;
 use crate::pnft::*;
 use crate::{context::*, error::Errors};
 
 declare_id!("7V5r5RdjfBLQY1iZAHhqR1Y6nuL8RgT8ituGCyEviY2t");
 
+// Batch withdrawal limits, sized so a full batch stays under the 1.4M compute unit
+// transaction ceiling and the transaction account limit
+pub const MAX_TOKEN_BATCH: usize = 16;
+pub const MAX_PNFT_BATCH: usize = 5;
+
 #[program]
 pub mod caravan {
-    use anchor_lang::solana_program::{
-        instruction::Instruction, program::invoke_signed, system_instruction::transfer,
-    };
+    use anchor_lang::solana_program::{program::invoke, system_instruction::transfer};
 
     use super::*;
 
@@ -31,42 +60,51 @@ pub mod caravan {
         ctx.accounts.vault.vault_id = vault_id;
         ctx.accounts.vault.owner = ctx.accounts.payer.key();
         ctx.accounts.vault.is_frozen = false;
+        ctx.accounts.vault.bump = *ctx.bumps.get("vault").unwrap();
+        ctx.accounts.vault.active_trade = None;
+
+        ctx.accounts.manifest.vault = ctx.accounts.vault.key();
+        ctx.accounts.manifest.bump = *ctx.bumps.get("manifest").unwrap();
+        ctx.accounts.manifest.entries = vec![];
         Ok(())
     }
 
-    // Deposits are done as standard TX
-    // You can know what NFTs the Vault has by indexing it.
+    // Deposits go through the program so the Vault's manifest stays in sync
+    // with what the Vault actually holds.
 
-    // Withdraw NFT from Vault (Token Program)
-    pub fn withdraw_nft(ctx: Context<WithdrawNFT>, vault_id: u64) -> Result<()> {
-        let payer_account_bytes = ctx.accounts.payer.key().to_bytes();
-        let vault_id_bytes = vault_id.to_be_bytes();
-        let vault_seeds: &[&[u8]] = &[
-            b"vault",
-            payer_account_bytes.as_ref(),
-            vault_id_bytes.as_ref(),
-            &[*ctx.bumps.get("vault").unwrap()],
-        ];
-        let signers_seeds = &[vault_seeds];
-
-        transferSPL(
-            CpiContext::new_with_signer(
+    // Deposit NFT into Vault (Token Program)
+    pub fn deposit_nft(ctx: Context<DepositNFT>, _vault_id: u64) -> Result<()> {
+        transfer_checked(
+            CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                TransferNFT {
-                    from: ctx.accounts.vault_ata.to_account_info(),
-                    to: ctx.accounts.receiver_ata.to_account_info(),
-                    authority: ctx.accounts.vault.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.payer_ata.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    to: ctx.accounts.vault_ata.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
                 },
-                signers_seeds,
             ),
             1,
+            ctx.accounts.mint.decimals,
         )?;
+
+        // Only mints with Token Metadata carry royalties, anything else is tracked as a token
+        let kind = if *ctx.accounts.metadata.owner == mpl_token_metadata::id() {
+            assert_decode_metadata(&ctx.accounts.mint, &ctx.accounts.metadata.to_account_info())?;
+            AssetKind::Nft
+        } else {
+            AssetKind::Spl
+        };
+        ctx.accounts
+            .manifest
+            .add(kind, ctx.accounts.vault_ata.mint, 1)?;
         Ok(())
     }
-    // Withdraw pNFT from Vault (MPL Token Program)
-    pub fn withdraw_pnft<'info>(
-        ctx: Context<'_, '_, '_, 'info, WithdrawPNFT<'info>>,
-        vault_id: u64,
+
+    // Deposit pNFT into Vault (MPL Token Program)
+    pub fn deposit_pnft<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositPNFT<'info>>,
+        _vault_id: u64,
         authorization_data: Option<AuthorizationDataLocal>,
         rules_acc_present: bool,
     ) -> Result<()> {
@@ -77,326 +115,799 @@ pub mod caravan {
             None
         };
 
-        let mut builder = TransferBuilder::new();
-
-        builder
-            .token(ctx.accounts.vault_ata.key())
-            .token_owner(ctx.accounts.vault.key())
-            .authority(ctx.accounts.vault.key())
-            .destination(ctx.accounts.receiver_ata.key())
-            .destination_owner(ctx.accounts.receiver.key())
-            .mint(ctx.accounts.nft_mint.key())
-            .metadata(ctx.accounts.metadata.key())
-            .edition(ctx.accounts.edition.key())
-            .payer(ctx.accounts.payer.key())
-            .owner_token_record(ctx.accounts.owner_token_record.key())
-            .destination_token_record(ctx.accounts.receiver_token_record.key());
-
-        let mut account_infos = vec![
-            //   0. `[writable]` Token account
-            ctx.accounts.vault_ata.to_account_info(),
-            //   1. `[]` Token account owner
-            ctx.accounts.vault.to_account_info(),
-            //   2. `[writable]` Destination token account
-            ctx.accounts.receiver_ata.to_account_info(),
-            //   3. `[]` Destination token account owner
-            ctx.accounts.receiver.to_account_info(),
-            //   4. `[]` Mint of token asset
-            ctx.accounts.nft_mint.to_account_info(),
-            //   5. `[writable]` Metadata account
-            ctx.accounts.metadata.to_account_info(),
-            //   6. `[optional]` Edition of token asset
-            ctx.accounts.edition.to_account_info(),
-            //   7. `[signer] Transfer authority (token or delegate owner)
-            ctx.accounts.receiver.to_account_info(),
-            //   8. `[optional, writable]` Owner record PDA
-            ctx.accounts.owner_token_record.to_account_info(),
-            //   9. `[optional, writable]` Destination record PDA
-            ctx.accounts.receiver_token_record.to_account_info(),
-            //   10. `[signer, writable]` Payer
-            ctx.accounts.payer.to_account_info(),
-            //   11. `[]` System Program
-            ctx.accounts.system_program.to_account_info(),
-            //   12. `[]` Instructions sysvar account
-            ctx.accounts.sysvar_instructions.to_account_info(),
-            //   13. `[]` SPL Token Program
-            ctx.accounts.token_program.to_account_info(),
-            //   14. `[]` SPL Associated Token Account program
-            ctx.accounts.associated_token_program.to_account_info(),
-            //   15. `[optional]` Token Authorization Rules Program
-            ctx.accounts.auth_rules_program.to_account_info(),
-            //   16. `[optional]` Token Authorization Rules account
-            // ctx.accounts.auth_rules_pda.to_account_info(),
-        ];
-
         let metadata = assert_decode_metadata(
             &ctx.accounts.nft_mint,
             &ctx.accounts.metadata.to_account_info(),
         )?;
 
-        //if auth rules passed in, validate & include it in CPI call
-        if let Some(config) = metadata.programmable_config {
-            match config {
-                V1 { rule_set } => {
-                    if let Some(rule_set) = rule_set {
-                        msg!("ruleset triggered");
-                        //safe to unwrap here, it's expected
-                        let rules_acc = auth_rules.unwrap();
-
-                        //1. validate
-                        require!(rule_set == *rules_acc.key, Errors::BadRuleset);
-
-                        //2. add to builder
-                        builder.authorization_rules_program(*ctx.accounts.auth_rules_program.key);
-                        builder.authorization_rules(*rules_acc.key);
-
-                        //3. add to accounts
-                        account_infos.push(ctx.accounts.auth_rules_program.to_account_info());
-                        account_infos.push(rules_acc.to_account_info());
-                    }
-                }
-            }
-        }
+        transfer_pnft(
+            PNftTransfer {
+                source_ata: ctx.accounts.payer_ata.to_account_info(),
+                source_owner: ctx.accounts.payer.to_account_info(),
+                destination_ata: ctx.accounts.vault_ata.to_account_info(),
+                destination_owner: ctx.accounts.vault.to_account_info(),
+                nft_mint: ctx.accounts.nft_mint.to_account_info(),
+                metadata: ctx.accounts.metadata.to_account_info(),
+                edition: ctx.accounts.edition.to_account_info(),
+                owner_token_record: ctx.accounts.owner_token_record.to_account_info(),
+                destination_token_record: ctx.accounts.vault_token_record.to_account_info(),
+                payer: ctx.accounts.payer.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+                sysvar_instructions: ctx.accounts.sysvar_instructions.to_account_info(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+                associated_token_program: ctx.accounts.associated_token_program.to_account_info(),
+                auth_rules_program: ctx.accounts.auth_rules_program.to_account_info(),
+                auth_rules: auth_rules.cloned(),
+            },
+            &metadata,
+            authorization_data,
+            &[],
+        )?;
 
-        let transfer_ix = builder
-            .build(TransferArgs::V1 {
-                amount: 1, //currently 1 only
-                authorization_data: authorization_data.map(|authorization_data| {
-                    AuthorizationData::try_from(authorization_data).unwrap()
-                }),
-            })
-            .unwrap()
-            .instruction();
+        ctx.accounts
+            .manifest
+            .add(AssetKind::PNft, ctx.accounts.nft_mint.key(), 1)?;
+        Ok(())
+    }
+
+    // Deposit cNFT into Vault (MPL Bubblegum Program)
+    pub fn deposit_cnft<'info>(
+        ctx: Context<'_, '_, '_, 'info, DepositCNFT<'info>>,
+        _vault_id: u64,
+        root: [u8; 32],
+        data_hash: [u8; 32],
+        creator_hash: [u8; 32],
+        nonce: u64,
+        index: u32,
+    ) -> Result<()> {
+        transfer_cnft(
+            CNftTransfer {
+                bubblegum_program: ctx.accounts.bubblegum_program.to_account_info(),
+                tree_authority: ctx.accounts.tree_authority.to_account_info(),
+                leaf_owner: ctx.accounts.payer.to_account_info(),
+                new_leaf_owner: ctx.accounts.vault.to_account_info(),
+                merkle_tree: ctx.accounts.merkle_tree.to_account_info(),
+                log_wrapper: ctx.accounts.log_wrapper.to_account_info(),
+                compression_program: ctx.accounts.compression_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+                proof: ctx.remaining_accounts,
+            },
+            root,
+            data_hash,
+            creator_hash,
+            nonce,
+            index,
+            &[],
+        )?;
+
+        ctx.accounts.manifest.add(
+            AssetKind::CNft,
+            get_asset_id(&ctx.accounts.merkle_tree.key(), nonce),
+            1,
+        )?;
+        Ok(())
+    }
+
+    // Deposit SPL Token
+    pub fn deposit_spl(ctx: Context<DepositSPL>, _vault_id: u64, amount: u64) -> Result<()> {
+        transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.payer_ata.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    to: ctx.accounts.vault_ata.to_account_info(),
+                    authority: ctx.accounts.payer.to_account_info(),
+                },
+            ),
+            amount,
+            ctx.accounts.mint.decimals,
+        )?;
+
+        // Token-2022 transfer fees are withheld in the vault's account, only record what arrived
+        let received = amount - transfer_fee(&ctx.accounts.mint, amount)?;
+        ctx.accounts
+            .manifest
+            .add(AssetKind::Spl, ctx.accounts.vault_ata.mint, received)?;
+        Ok(())
+    }
+
+    // Deposit SOL
+    pub fn deposit_sol(ctx: Context<DepositSOL>, _vault_id: u64, amount: u64) -> Result<()> {
+        invoke(
+            &transfer(&ctx.accounts.payer.key(), &ctx.accounts.vault.key(), amount),
+            &[
+                ctx.accounts.payer.to_account_info(),
+                ctx.accounts.vault.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        ctx.accounts
+            .manifest
+            .add(AssetKind::Sol, System::id(), amount)?;
+        Ok(())
+    }
+
+    // Deposit Core asset into Vault (MPL Core Program)
+    pub fn deposit_core(ctx: Context<DepositCore>, _vault_id: u64) -> Result<()> {
+        transfer_core(
+            &ctx.accounts.core_program.to_account_info(),
+            &ctx.accounts.asset.to_account_info(),
+            ctx.accounts
+                .collection
+                .as_ref()
+                .map(|collection| collection.as_ref()),
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.vault.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            &[],
+        )?;
+
+        ctx.accounts
+            .manifest
+            .add(AssetKind::Core, ctx.accounts.asset.key(), 1)?;
+        Ok(())
+    }
+
+    // Withdraw NFT from Vault (Token Program)
+    pub fn withdraw_nft<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawNFT<'info>>,
+        _vault_id: u64,
+        close_source: bool,
+    ) -> Result<()> {
+        prepare_receiver_ata(
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+            &ctx.accounts.receiver,
+            &ctx.accounts.receiver_ata,
+            &ctx.accounts.mint,
+            &ctx.accounts.token_program,
+            ctx.accounts.associated_token_program.as_ref(),
+        )?;
 
-        let payer_account_bytes = ctx.accounts.payer.key().to_bytes();
-        let vault_id_bytes = vault_id.to_be_bytes();
-        let vault_seeds: &[&[u8]] = &[
-            b"vault",
-            payer_account_bytes.as_ref(),
-            vault_id_bytes.as_ref(),
-            &[*ctx.bumps.get("vault").unwrap()],
-        ];
-        let signers_seeds = &[vault_seeds];
+        ctx.accounts.vault.with_signer_seeds(|signers_seeds| {
+            // Remaining accounts: extra accounts for a Token-2022 transfer hook, if the mint has one
+            transfer_checked_with_hook(
+                &ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.vault_ata.to_account_info(),
+                ctx.accounts.mint.to_account_info(),
+                ctx.accounts.receiver_ata.to_account_info(),
+                ctx.accounts.vault.to_account_info(),
+                ctx.remaining_accounts,
+                1,
+                ctx.accounts.mint.decimals,
+                signers_seeds,
+            )?;
+            Ok(())
+        })?;
 
-        invoke_signed(&transfer_ix, &account_infos, signers_seeds)?;
+        ctx.accounts.manifest.remove(ctx.accounts.vault_ata.mint, 1);
 
+        // Reclaim the emptied token account's rent for the vault owner
+        if close_source {
+            ctx.accounts.vault_ata.reload()?;
+            if ctx.accounts.vault_ata.amount == 0 {
+                close_empty_token_accounts(
+                    &ctx.accounts.vault,
+                    &[ctx.accounts.vault_ata.to_account_info()],
+                    &[ctx.accounts.token_program.to_account_info()],
+                    &ctx.accounts.owner.to_account_info(),
+                )?;
+            }
+        }
         Ok(())
     }
+    // Withdraw pNFT from Vault (MPL Token Program)
+    pub fn withdraw_pnft<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawPNFT<'info>>,
+        _vault_id: u64,
+        authorization_data: Option<AuthorizationDataLocal>,
+        rules_acc_present: bool,
+    ) -> Result<()> {
+        let rem_acc = &mut ctx.remaining_accounts.iter();
+        let auth_rules = if rules_acc_present {
+            Some(next_account_info(rem_acc)?)
+        } else {
+            None
+        };
+
+        let nft_metadata = assert_decode_metadata(
+            &ctx.accounts.nft_mint,
+            &ctx.accounts.metadata.to_account_info(),
+        )?;
+
+        ctx.accounts.vault.with_signer_seeds(|signers_seeds| {
+            transfer_pnft(
+                PNftTransfer {
+                    source_ata: ctx.accounts.vault_ata.to_account_info(),
+                    source_owner: ctx.accounts.vault.to_account_info(),
+                    destination_ata: ctx.accounts.receiver_ata.to_account_info(),
+                    destination_owner: ctx.accounts.receiver.to_account_info(),
+                    nft_mint: ctx.accounts.nft_mint.to_account_info(),
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    edition: ctx.accounts.edition.to_account_info(),
+                    owner_token_record: ctx.accounts.owner_token_record.to_account_info(),
+                    destination_token_record: ctx.accounts.receiver_token_record.to_account_info(),
+                    payer: ctx.accounts.payer.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    sysvar_instructions: ctx.accounts.sysvar_instructions.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: ctx
+                        .accounts
+                        .associated_token_program
+                        .to_account_info(),
+                    auth_rules_program: ctx.accounts.auth_rules_program.to_account_info(),
+                    auth_rules: auth_rules.cloned(),
+                },
+                &nft_metadata,
+                authorization_data,
+                signers_seeds,
+            )
+        })?;
 
-    // first 8 bytes of SHA256("global:transfer")
-    const TRANSFER_DISCRIMINATOR: &'static [u8; 8] = &[163, 52, 200, 231, 140, 3, 69, 186];
+        ctx.accounts.manifest.remove(ctx.accounts.nft_mint.key(), 1);
+        Ok(())
+    }
 
     // Withdraw cNFT from Vault (MPL Bubblegum Program)
     pub fn withdraw_cnft<'info>(
         //Explicitly set the CTX lifetime to the function lifetime
         // This allows all the accountinfos to last til the end of the function call
         ctx: Context<'_, '_, '_, 'info, WithdrawCNFT<'info>>,
-        vault_id: u64,
+        _vault_id: u64,
         root: [u8; 32],
         data_hash: [u8; 32],
         creator_hash: [u8; 32],
         nonce: u64,
         index: u32,
     ) -> Result<()> {
-        let mut accounts: Vec<AccountMeta> = vec![
-            AccountMeta::new_readonly(ctx.accounts.tree_authority.key(), false),
-            AccountMeta::new_readonly(ctx.accounts.vault.key(), true),
-            AccountMeta::new_readonly(ctx.accounts.vault.key(), false),
-            AccountMeta::new_readonly(ctx.accounts.new_leaf_owner.key(), false),
-            AccountMeta::new(ctx.accounts.merkle_tree.key(), false),
-            AccountMeta::new_readonly(ctx.accounts.log_wrapper.key(), false),
-            AccountMeta::new_readonly(ctx.accounts.compression_program.key(), false),
-            AccountMeta::new_readonly(ctx.accounts.system_program.key(), false),
-        ];
-
-        let mut data: Vec<u8> = vec![];
-        data.extend(TRANSFER_DISCRIMINATOR);
-        data.extend(root);
-        data.extend(data_hash);
-        data.extend(creator_hash);
-        data.extend(nonce.to_le_bytes());
-        data.extend(index.to_le_bytes());
-
-        let mut account_infos: Vec<AccountInfo> = vec![
-            ctx.accounts.tree_authority.to_account_info(),
-            ctx.accounts.vault.to_account_info(),
-            ctx.accounts.vault.to_account_info(),
-            ctx.accounts.new_leaf_owner.to_account_info(),
-            ctx.accounts.merkle_tree.to_account_info(),
-            ctx.accounts.log_wrapper.to_account_info(),
-            ctx.accounts.compression_program.to_account_info(),
-            ctx.accounts.system_program.to_account_info(),
-        ];
-
-        for acc in ctx.remaining_accounts.iter() {
-            accounts.push(AccountMeta::new_readonly(acc.key(), false));
-            account_infos.push(acc.to_account_info());
-        }
-
-        let payer_account_bytes = ctx.accounts.payer.key().to_bytes();
-        let vault_id_bytes = vault_id.to_be_bytes();
-        let vault_seeds: &[&[u8]] = &[
-            b"vault",
-            payer_account_bytes.as_ref(),
-            vault_id_bytes.as_ref(),
-            &[*ctx.bumps.get("vault").unwrap()],
-        ];
-        let signers_seeds = &[vault_seeds];
-
-        invoke_signed(
-            &Instruction {
-                program_id: ctx.accounts.bubblegum_program.key(),
-                accounts,
-                data,
-            },
-            account_infos.as_slice(),
-            signers_seeds,
-        )?;
+        ctx.accounts.vault.with_signer_seeds(|signers_seeds| {
+            transfer_cnft(
+                CNftTransfer {
+                    bubblegum_program: ctx.accounts.bubblegum_program.to_account_info(),
+                    tree_authority: ctx.accounts.tree_authority.to_account_info(),
+                    leaf_owner: ctx.accounts.vault.to_account_info(),
+                    new_leaf_owner: ctx.accounts.new_leaf_owner.to_account_info(),
+                    merkle_tree: ctx.accounts.merkle_tree.to_account_info(),
+                    log_wrapper: ctx.accounts.log_wrapper.to_account_info(),
+                    compression_program: ctx.accounts.compression_program.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    proof: ctx.remaining_accounts,
+                },
+                root,
+                data_hash,
+                creator_hash,
+                nonce,
+                index,
+                signers_seeds,
+            )
+        })?;
+
+        ctx.accounts
+            .manifest
+            .remove(get_asset_id(&ctx.accounts.merkle_tree.key(), nonce), 1);
         Ok(())
     }
 
     // Withdraw SPL Token
-    pub fn withdraw_spl(ctx: Context<WithdrawSPL>, vault_id: u64, amount: u64) -> Result<()> {
-        let payer_account_bytes = ctx.accounts.payer.key().to_bytes();
-        let vault_id_bytes = vault_id.to_be_bytes();
-        let vault_seeds: &[&[u8]] = &[
-            b"vault",
-            payer_account_bytes.as_ref(),
-            vault_id_bytes.as_ref(),
-            &[*ctx.bumps.get("vault").unwrap()],
-        ];
-        let signers_seeds = &[vault_seeds];
-
-        transferSPL(
-            CpiContext::new_with_signer(
-                ctx.accounts.token_program.to_account_info(),
-                TransferNFT {
-                    from: ctx.accounts.vault_ata.to_account_info(),
-                    to: ctx.accounts.receiver_ata.to_account_info(),
-                    authority: ctx.accounts.vault.to_account_info(),
-                },
-                signers_seeds,
-            ),
-            amount,
+    pub fn withdraw_spl<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawSPL<'info>>,
+        _vault_id: u64,
+        amount: u64,
+        close_source: bool,
+    ) -> Result<()> {
+        prepare_receiver_ata(
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+            &ctx.accounts.receiver,
+            &ctx.accounts.receiver_ata,
+            &ctx.accounts.mint,
+            &ctx.accounts.token_program,
+            ctx.accounts.associated_token_program.as_ref(),
         )?;
+
+        ctx.accounts.vault.with_signer_seeds(|signers_seeds| {
+            // With a transfer-fee mint the receiver gets amount less the mint's fee
+            // Remaining accounts: extra accounts for a Token-2022 transfer hook, if the mint has one
+            transfer_checked_with_hook(
+                &ctx.accounts.token_program.to_account_info(),
+                ctx.accounts.vault_ata.to_account_info(),
+                ctx.accounts.mint.to_account_info(),
+                ctx.accounts.receiver_ata.to_account_info(),
+                ctx.accounts.vault.to_account_info(),
+                ctx.remaining_accounts,
+                amount,
+                ctx.accounts.mint.decimals,
+                signers_seeds,
+            )?;
+            Ok(())
+        })?;
+
+        ctx.accounts
+            .manifest
+            .remove(ctx.accounts.vault_ata.mint, amount);
+
+        // Reclaim the emptied token account's rent for the vault owner
+        if close_source {
+            ctx.accounts.vault_ata.reload()?;
+            if ctx.accounts.vault_ata.amount == 0 {
+                close_empty_token_accounts(
+                    &ctx.accounts.vault,
+                    &[ctx.accounts.vault_ata.to_account_info()],
+                    &[ctx.accounts.token_program.to_account_info()],
+                    &ctx.accounts.owner.to_account_info(),
+                )?;
+            }
+        }
         Ok(())
     }
 
     // Withdraw SOL
-    pub fn withdraw_sol(ctx: Context<WithdrawSOL>, vault_id: u64, amount: u64) -> Result<()> {
+    pub fn withdraw_sol(ctx: Context<WithdrawSOL>, _vault_id: u64, amount: u64) -> Result<()> {
         let rent = &Rent::from_account_info(&ctx.accounts.rent_sysvar.to_account_info())?;
-        let rent_exempt_resverve = rent.minimum_balance(Vault::get_size());
-        if ctx.accounts.vault.to_account_info().lamports() - amount < rent_exempt_resverve {
+        let rent_exempt_resverve = rent.minimum_balance(8 + Vault::get_size());
+        let vault_lamports = ctx.accounts.vault.to_account_info().lamports();
+        if vault_lamports.saturating_sub(amount) < rent_exempt_resverve {
             return err!(Errors::SolRentExemptViolation);
         }
 
-        let transfer_ix = transfer(
-            &ctx.accounts.vault.key(),
-            &ctx.accounts.receiver.key(),
-            amount,
-        );
+        // The Vault is owned by this program and carries data, so the System Program
+        // can't move lamports out of it; debit it directly instead.
+        **ctx
+            .accounts
+            .vault
+            .to_account_info()
+            .try_borrow_mut_lamports()? -= amount;
+        **ctx.accounts.receiver.try_borrow_mut_lamports()? += amount;
 
-        let payer_account_bytes = ctx.accounts.payer.key().to_bytes();
-        let vault_id_bytes = vault_id.to_be_bytes();
-        let vault_seeds: &[&[u8]] = &[
-            b"vault",
-            payer_account_bytes.as_ref(),
-            vault_id_bytes.as_ref(),
-            &[*ctx.bumps.get("vault").unwrap()],
-        ];
-        let signers_seeds = &[vault_seeds];
-
-        invoke_signed(
-            &transfer_ix,
-            &[
-                ctx.accounts.vault.to_account_info(),
-                ctx.accounts.receiver.to_account_info(),
-                ctx.accounts.system_program.to_account_info(),
-            ],
-            signers_seeds,
+        ctx.accounts.manifest.remove(System::id(), amount);
+        Ok(())
+    }
+
+    // Withdraw Core asset from Vault (MPL Core Program)
+    pub fn withdraw_core(ctx: Context<WithdrawCore>, _vault_id: u64) -> Result<()> {
+        ctx.accounts.vault.with_signer_seeds(|signers_seeds| {
+            transfer_core(
+                &ctx.accounts.core_program.to_account_info(),
+                &ctx.accounts.asset.to_account_info(),
+                ctx.accounts
+                    .collection
+                    .as_ref()
+                    .map(|collection| collection.as_ref()),
+                &ctx.accounts.payer.to_account_info(),
+                &ctx.accounts.vault.to_account_info(),
+                &ctx.accounts.receiver.to_account_info(),
+                &ctx.accounts.system_program.to_account_info(),
+                signers_seeds,
+            )?;
+            Ok(())
+        })?;
+
+        ctx.accounts.manifest.remove(ctx.accounts.asset.key(), 1);
+        Ok(())
+    }
+
+    // Withdraw NFTs from Vault in one call (Token Program / Token-2022)
+    // Remaining accounts: [vault_ata, mint, receiver_ata] per NFT
+    pub fn withdraw_nft_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawTokenBatch<'info>>,
+        _vault_id: u64,
+    ) -> Result<()> {
+        let group_count = ctx.remaining_accounts.len() / 3;
+        withdraw_token_batch(
+            &ctx.accounts.vault,
+            &ctx.accounts.receiver.to_account_info(),
+            &mut ctx.accounts.manifest,
+            &ctx.accounts.token_program.to_account_info(),
+            ctx.remaining_accounts,
+            &vec![1; group_count],
+        )
+    }
+
+    // Withdraw SPL Tokens from Vault in one call (Token Program / Token-2022)
+    // Remaining accounts: [vault_ata, mint, receiver_ata] per token, `amounts` in the same order
+    pub fn withdraw_spl_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawTokenBatch<'info>>,
+        _vault_id: u64,
+        amounts: Vec<u64>,
+    ) -> Result<()> {
+        withdraw_token_batch(
+            &ctx.accounts.vault,
+            &ctx.accounts.receiver.to_account_info(),
+            &mut ctx.accounts.manifest,
+            &ctx.accounts.token_program.to_account_info(),
+            ctx.remaining_accounts,
+            &amounts,
+        )
+    }
+
+    // Withdraw pNFTs from Vault in one call (MPL Token Program), all to the same receiver
+    // Remaining accounts: [vault_ata, receiver_ata, mint, metadata, edition, owner_token_record,
+    // receiver_token_record] per pNFT
+    pub fn withdraw_pnft_batch<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawPNFTBatch<'info>>,
+        _vault_id: u64,
+        authorization_data: Option<AuthorizationDataLocal>,
+    ) -> Result<()> {
+        if ctx.remaining_accounts.len() % 7 != 0 {
+            return err!(Errors::MalformedBatch);
+        }
+        let group_count = ctx.remaining_accounts.len() / 7;
+        if group_count == 0 || group_count > MAX_PNFT_BATCH {
+            return err!(Errors::InvalidBatchSize);
+        }
+
+        for group in ctx.remaining_accounts.chunks(7) {
+            let [vault_ata, receiver_ata, nft_mint, metadata, edition, owner_token_record, receiver_token_record] =
+                group
+            else {
+                return err!(Errors::MalformedBatch);
+            };
+
+            // The Token Metadata program checks the edition and token records itself
+            let nft_mint_account: Account<Mint> = Account::try_from(nft_mint)?;
+            if vault_ata.key()
+                != anchor_spl::associated_token::get_associated_token_address(
+                    &ctx.accounts.vault.key(),
+                    &nft_mint.key(),
+                )
+                || receiver_ata.key()
+                    != anchor_spl::associated_token::get_associated_token_address(
+                        &ctx.accounts.receiver.key(),
+                        &nft_mint.key(),
+                    )
+            {
+                return err!(Errors::MalformedBatch);
+            }
+            let nft_metadata = assert_decode_metadata(&nft_mint_account, metadata)?;
+
+            ctx.accounts.vault.with_signer_seeds(|signers_seeds| {
+                transfer_pnft(
+                    PNftTransfer {
+                        source_ata: vault_ata.clone(),
+                        source_owner: ctx.accounts.vault.to_account_info(),
+                        destination_ata: receiver_ata.clone(),
+                        destination_owner: ctx.accounts.receiver.to_account_info(),
+                        nft_mint: nft_mint.clone(),
+                        metadata: metadata.clone(),
+                        edition: edition.clone(),
+                        owner_token_record: owner_token_record.clone(),
+                        destination_token_record: receiver_token_record.clone(),
+                        payer: ctx.accounts.payer.to_account_info(),
+                        system_program: ctx.accounts.system_program.to_account_info(),
+                        sysvar_instructions: ctx.accounts.sysvar_instructions.to_account_info(),
+                        token_program: ctx.accounts.token_program.to_account_info(),
+                        associated_token_program: ctx
+                            .accounts
+                            .associated_token_program
+                            .to_account_info(),
+                        auth_rules_program: ctx.accounts.auth_rules_program.to_account_info(),
+                        auth_rules: ctx
+                            .accounts
+                            .auth_rules
+                            .as_ref()
+                            .map(|a| a.to_account_info()),
+                    },
+                    &nft_metadata,
+                    authorization_data.clone(),
+                    signers_seeds,
+                )
+            })?;
+
+            ctx.accounts.manifest.remove(nft_mint.key(), 1);
+        }
+        Ok(())
+    }
+
+    // Close Vault
+    // Remaining accounts: vault token accounts to close, they must all be empty.
+    // Closing the vault hands every lamport it holds to the receiver.
+    pub fn close_vault<'info>(ctx: Context<'_, '_, '_, 'info, CloseVault<'info>>) -> Result<()> {
+        if ctx.accounts.manifest.holds_assets() {
+            return err!(Errors::VaultNotEmpty);
+        }
+
+        close_empty_token_accounts(
+            &ctx.accounts.vault,
+            ctx.remaining_accounts,
+            &token_program_infos(
+                ctx.accounts.token_program.as_ref(),
+                ctx.accounts.token_2022_program.as_ref(),
+            ),
+            &ctx.accounts.receiver.to_account_info(),
+        )
+    }
+
+    // Close Vault Token Accounts
+    // Remaining accounts: empty vault token accounts, their rent goes to the vault owner
+    pub fn close_vault_token_accounts<'info>(
+        ctx: Context<'_, '_, '_, 'info, CloseVaultTokenAccounts<'info>>,
+    ) -> Result<()> {
+        close_empty_token_accounts(
+            &ctx.accounts.vault,
+            ctx.remaining_accounts,
+            &token_program_infos(
+                ctx.accounts.token_program.as_ref(),
+                ctx.accounts.token_2022_program.as_ref(),
+            ),
+            &ctx.accounts.owner.to_account_info(),
+        )
+    }
+
+    // Grant Delegate
+    // Lets another wallet act on the vault with the given permissions until expires_at
+    pub fn grant_delegate(
+        ctx: Context<GrantDelegate>,
+        permissions: u8,
+        expires_at: Option<i64>,
+    ) -> Result<()> {
+        set_delegate_grant(
+            &mut ctx.accounts.vault_delegate,
+            ctx.accounts.vault.owner,
+            permissions,
+            expires_at,
         )?;
+        ctx.accounts.vault_delegate.vault = ctx.accounts.vault.key();
+        ctx.accounts.vault_delegate.delegate = ctx.accounts.delegate.key();
+        ctx.accounts.vault_delegate.bump = *ctx.bumps.get("vault_delegate").unwrap();
+        Ok(())
+    }
 
+    // Update Delegate
+    pub fn update_delegate(
+        ctx: Context<UpdateDelegate>,
+        permissions: u8,
+        expires_at: Option<i64>,
+    ) -> Result<()> {
+        set_delegate_grant(
+            &mut ctx.accounts.vault_delegate,
+            ctx.accounts.vault.owner,
+            permissions,
+            expires_at,
+        )
+    }
+
+    // Revoke Delegate
+    // Closing the grant returns its rent
+    pub fn revoke_delegate(_ctx: Context<RevokeDelegate>) -> Result<()> {
         Ok(())
     }
 
     // Create Trade
     // Freezes Vault Withdraws
-    pub fn create_trade(ctx: Context<CreateTrade>, trade_id: u64) -> Result<()> {
+    pub fn create_trade(
+        ctx: Context<CreateTrade>,
+        trade_id: u64,
+        expires_at: Option<i64>,
+        allowed_acceptor: Option<Pubkey>,
+        payment: Option<TradePayment>,
+    ) -> Result<()> {
+        if let Some(payment) = payment {
+            if payment.amount == 0 {
+                return err!(Errors::InvalidPayment);
+            }
+        }
+        if let Some(expires_at) = expires_at {
+            if expires_at <= Clock::get()?.unix_timestamp {
+                return err!(Errors::InvalidExpiry);
+            }
+        }
+
         ctx.accounts.trade.trade_id = trade_id;
         ctx.accounts.trade.creator_key = ctx.accounts.payer.key();
-        ctx.accounts.trade.creator_vault_key = None;
+        ctx.accounts.trade.bump = *ctx.bumps.get("trade").unwrap();
+        ctx.accounts.trade.creator_vault_keys = vec![];
         ctx.accounts.trade.acceptor_key = None;
-        ctx.accounts.trade.acceptor_vault_key = None;
+        ctx.accounts.trade.acceptor_vault_keys = vec![];
         ctx.accounts.trade.creator_confirmed = false;
         ctx.accounts.trade.acceptor_confirmed = false;
+        ctx.accounts.trade.creator_terms = [0; 32];
+        ctx.accounts.trade.acceptor_terms = [0; 32];
+        ctx.accounts.trade.status = TradeStatus::Open;
+        ctx.accounts.trade.expires_at = expires_at;
+        ctx.accounts.trade.allowed_acceptor = allowed_acceptor;
+        ctx.accounts.trade.payment = payment;
+        ctx.accounts.trade.payment_escrowed = false;
         Ok(())
     }
 
+    // Open -> Joined
     pub fn join_trade(ctx: Context<JoinTrade>) -> Result<()> {
-        if ctx.accounts.trade.acceptor_key == None {
-            ctx.accounts.trade.acceptor_key = Some(ctx.accounts.payer.key())
-        } else {
-            return err!(Errors::TradeFull);
+        if ctx.accounts.trade.status != TradeStatus::Open {
+            return err!(Errors::TradeAlreadyJoined);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+
+        if let Some(allowed_acceptor) = ctx.accounts.trade.allowed_acceptor {
+            if allowed_acceptor != ctx.accounts.payer.key() {
+                return err!(Errors::AcceptorNotAllowed);
+            }
         }
 
+        ctx.accounts.trade.acceptor_key = Some(ctx.accounts.payer.key());
+        ctx.accounts.trade.refresh_status();
         Ok(())
     }
 
     // Attach vault to trade
+    // Each side can put in up to MAX_VAULTS_PER_SIDE vaults
+    // Open/Joined/Attached -> Attached once both sides have a vault in
     pub fn attach_vault_to_trade(ctx: Context<AttachVault>) -> Result<()> {
-        if ctx.accounts.trade.creator_key == ctx.accounts.payer.key()
-            && !ctx.accounts.trade.creator_confirmed
-        {
+        if !matches!(
+            ctx.accounts.trade.status,
+            TradeStatus::Open | TradeStatus::Joined | TradeStatus::Attached
+        ) {
+            return err!(Errors::TradeNotAttachable);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+
+        let trade_key = ctx.accounts.trade.key();
+        let vault_key = ctx.accounts.vault.key();
+        // The vault goes on its owner's side, so a delegate can't put it on a side it controls
+        let party = ctx.accounts.vault.owner;
+        let trade = &mut ctx.accounts.trade;
+        let side_vault_keys = if trade.creator_key == party && !trade.creator_confirmed {
             // Can't change out vaults if you've confirmed
-            ctx.accounts.trade.creator_vault_key = Some(ctx.accounts.vault.key());
-            ctx.accounts.vault.is_frozen = true;
             // If you haven't confirmed and you change out vaults, the other person's confirmation gets reset
-            ctx.accounts.trade.acceptor_confirmed = false;
-        } else if ctx.accounts.trade.acceptor_key == Some(ctx.accounts.payer.key())
-            && !ctx.accounts.trade.acceptor_confirmed
-        {
+            trade.acceptor_confirmed = false;
+            &mut trade.creator_vault_keys
+        } else if trade.acceptor_key == Some(party) && !trade.acceptor_confirmed {
+            // In a payment trade the acceptor's side is the payment
+            if trade.payment.is_some() {
+                return err!(Errors::PaymentTradeVault);
+            }
             // Can't change out vaults if you've confirmed
-            ctx.accounts.trade.acceptor_vault_key = Some(ctx.accounts.vault.key());
-            ctx.accounts.vault.is_frozen = true;
             // If you haven't confirmed and you change out vaults, the other person's confirmation gets reset
-            ctx.accounts.trade.creator_confirmed = false;
+            trade.creator_confirmed = false;
+            &mut trade.acceptor_vault_keys
         } else {
             return err!(Errors::VaultAttachError);
+        };
+
+        if !side_vault_keys.contains(&vault_key) {
+            if side_vault_keys.len() >= MAX_VAULTS_PER_SIDE {
+                return err!(Errors::TooManyVaults);
+            }
+            side_vault_keys.push(vault_key);
+        }
+        ctx.accounts.vault.freeze_for(trade_key);
+
+        ctx.accounts.trade.refresh_status();
+        Ok(())
+    }
+
+    // Detach vault from trade
+    // Pulls one of your vaults back out before you've locked
+    pub fn detach_vault_from_trade(ctx: Context<DetachVault>) -> Result<()> {
+        if !matches!(
+            ctx.accounts.trade.status,
+            TradeStatus::Open | TradeStatus::Joined | TradeStatus::Attached
+        ) {
+            return err!(Errors::TradeNotAttachable);
         }
+
+        let trade_key = ctx.accounts.trade.key();
+        let vault_key = ctx.accounts.vault.key();
+        // The vault comes off its owner's side, whether the owner or a delegate signs
+        let party = ctx.accounts.vault.owner;
+        let trade = &mut ctx.accounts.trade;
+        let side_vault_keys = if trade.creator_key == party && !trade.creator_confirmed {
+            trade.acceptor_confirmed = false;
+            &mut trade.creator_vault_keys
+        } else if trade.acceptor_key == Some(party) && !trade.acceptor_confirmed {
+            trade.creator_confirmed = false;
+            &mut trade.acceptor_vault_keys
+        } else {
+            return err!(Errors::VaultAttachError);
+        };
+
+        let position = side_vault_keys
+            .iter()
+            .position(|key| *key == vault_key)
+            .ok_or(Errors::VaultTradeMismatch)?;
+        side_vault_keys.remove(position);
+        ctx.accounts.vault.release_from(trade_key)?;
+
+        ctx.accounts.trade.refresh_status();
         Ok(())
     }
 
     // Lock trade
-    pub fn lock_trade(ctx: Context<LockTrade>) -> Result<()> {
-        if ctx.accounts.trade.creator_key == ctx.accounts.payer.key() {
+    // Locking pins the counterparty's vault contents so they can't be swapped out before confirm
+    // Attached -> Locked once both sides have locked
+    pub fn lock_trade<'info>(ctx: Context<'_, '_, '_, 'info, LockTrade<'info>>) -> Result<()> {
+        if ctx.accounts.trade.status != TradeStatus::Attached {
+            return err!(Errors::TradeNotLockable);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+
+        let (party, delegated_vault) = trade_party(
+            ctx.accounts.payer.key(),
+            ctx.accounts.vault.as_ref(),
+            ctx.accounts.vault_delegate.as_deref(),
+        )?;
+
+        if ctx.accounts.trade.creator_key == party {
+            if let Some(vault_key) = delegated_vault {
+                if !ctx.accounts.trade.creator_vault_keys.contains(&vault_key) {
+                    return err!(Errors::VaultTradeMismatch);
+                }
+            }
+            let terms = manifest_terms(
+                &ctx.accounts.trade.acceptor_vault_keys,
+                ctx.remaining_accounts,
+            )?;
+            ctx.accounts.trade.creator_terms = terms;
             ctx.accounts.trade.creator_confirmed = true;
-        } else if ctx.accounts.trade.acceptor_key == Some(ctx.accounts.payer.key()) {
+        } else if ctx.accounts.trade.acceptor_key == Some(party) {
+            if let Some(vault_key) = delegated_vault {
+                if !ctx.accounts.trade.acceptor_vault_keys.contains(&vault_key) {
+                    return err!(Errors::VaultTradeMismatch);
+                }
+            }
+            let terms = manifest_terms(
+                &ctx.accounts.trade.creator_vault_keys,
+                ctx.remaining_accounts,
+            )?;
+            ctx.accounts.trade.acceptor_terms = terms;
             ctx.accounts.trade.acceptor_confirmed = true;
+
+            // One-sided trade: the acceptor's lock puts the payment in escrow
+            if ctx.accounts.trade.payment.is_some() && !ctx.accounts.trade.payment_escrowed {
+                escrow_payment(
+                    &ctx.accounts.trade,
+                    &ctx.accounts.payer,
+                    &ctx.accounts.system_program,
+                    ctx.accounts.payer_token_account.as_ref(),
+                    ctx.accounts.payment_escrow.as_ref(),
+                    ctx.accounts.token_program.as_ref(),
+                )?;
+                ctx.accounts.trade.payment_escrowed = true;
+            }
         } else {
             return err!(Errors::VaultLockError);
         }
+
+        ctx.accounts.trade.refresh_status();
         Ok(())
     }
 
     // Cancel Trade
-    pub fn cancel_trade(ctx: Context<CancelTrade>) -> Result<()> {
+    // Creator: any live state, the trade is closed
+    // Acceptor: any live state -> Open (acceptor leaves)
+    pub fn cancel_trade<'info>(ctx: Context<'_, '_, '_, 'info, CancelTrade<'info>>) -> Result<()> {
+        let trade_key = ctx.accounts.trade.key();
+
         if ctx.accounts.trade.creator_key == ctx.accounts.payer.key() {
-            // If the creator wants to cancel, close the whole trade account
-            if ctx.accounts.trade.creator_vault_key.is_some()
-                && ctx.accounts.creator_vault.as_mut().unwrap().owner == ctx.accounts.payer.key()
-            {
-                ctx.accounts.creator_vault.as_mut().unwrap().is_frozen = false;
-            } else {
-                return err!(Errors::CancelTradeError);
-            }
+            // If the creator wants to cancel, unfreeze whichever vaults made it in and close the whole trade account
+            let vault_keys = ctx.accounts.trade.all_vault_keys();
+            release_trade_vaults(&vault_keys, ctx.remaining_accounts, None, trade_key)?;
 
-            if ctx.accounts.trade.acceptor_vault_key.is_some()
-                && ctx.accounts.acceptor_vault.as_mut().unwrap().owner
-                    == ctx.accounts.trade.acceptor_key.unwrap().key()
-            {
-                ctx.accounts.acceptor_vault.as_mut().unwrap().is_frozen = false;
-            } else {
-                return err!(Errors::CancelTradeError);
+            if ctx.accounts.trade.payment_escrowed {
+                let acceptor = ctx
+                    .accounts
+                    .acceptor
+                    .as_ref()
+                    .ok_or(Errors::PaymentAccountsMissing)?;
+                if Some(acceptor.key()) != ctx.accounts.trade.acceptor_key {
+                    return err!(Errors::PaymentAccountsMismatch);
+                }
+                release_payment(
+                    &ctx.accounts.trade,
+                    &acceptor.to_account_info(),
+                    ctx.accounts.payment_escrow.as_ref(),
+                    ctx.accounts.acceptor_token_account.as_ref(),
+                    ctx.accounts.token_program.as_ref(),
+                    None,
+                )?;
             }
 
             ctx.accounts
@@ -404,10 +915,31 @@ pub mod caravan {
                 .close(ctx.accounts.payer.to_account_info())?;
         } else if ctx.accounts.trade.acceptor_key == Some(ctx.accounts.payer.key()) {
             // If the counterparty wants to cancel, just reset the counterparty
+            release_trade_vaults(
+                &ctx.accounts.trade.acceptor_vault_keys,
+                ctx.remaining_accounts,
+                None,
+                trade_key,
+            )?;
+
+            if ctx.accounts.trade.payment_escrowed {
+                release_payment(
+                    &ctx.accounts.trade,
+                    &ctx.accounts.payer.to_account_info(),
+                    ctx.accounts.payment_escrow.as_ref(),
+                    ctx.accounts.acceptor_token_account.as_ref(),
+                    ctx.accounts.token_program.as_ref(),
+                    None,
+                )?;
+                ctx.accounts.trade.payment_escrowed = false;
+            }
+
             ctx.accounts.trade.acceptor_key = None;
-            ctx.accounts.trade.acceptor_vault_key = None;
+            ctx.accounts.trade.acceptor_vault_keys = vec![];
             ctx.accounts.trade.acceptor_confirmed = false;
-            ctx.accounts.acceptor_vault.as_mut().unwrap().is_frozen = false;
+            // The creator's lock was against the vaults that just left
+            ctx.accounts.trade.creator_confirmed = false;
+            ctx.accounts.trade.refresh_status();
         } else {
             return err!(Errors::CancelTradeError);
         }
@@ -415,46 +947,1707 @@ pub mod caravan {
     }
 
     // Confirm Trade
-    pub fn confirm_trade(ctx: Context<ConfirmTrade>) -> Result<()> {
+    // Locked, the trade is settled and closed
+    pub fn confirm_trade<'info>(
+        ctx: Context<'_, '_, '_, 'info, ConfirmTrade<'info>>,
+    ) -> Result<()> {
+        if ctx.accounts.trade.status != TradeStatus::Locked {
+            return err!(Errors::TradeNotConfirmable);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+
         // Checks:
         //1. Confirm must be called by creator or acceptor
-        //2. Creator and Acceptor must both have locked
-        //3. Creator Vault and Accept vault must match those sent in
+        //2. Creator and Acceptor must both have locked (and paid, in a payment trade)
+        //3. Creator Vaults and Acceptor vaults must match those sent in
+        //4. Vault contents must match what each side locked against
 
         if (ctx.accounts.trade.creator_key != ctx.accounts.payer.key()
             && ctx.accounts.trade.acceptor_key != Some(ctx.accounts.payer.key()))
             || (!ctx.accounts.trade.creator_confirmed || !ctx.accounts.trade.acceptor_confirmed)
-            || (ctx.accounts.trade.creator_vault_key != Some(ctx.accounts.creator_vault.key()))
-            || (ctx.accounts.trade.acceptor_vault_key != Some(ctx.accounts.acceptor_vault.key()))
+            || (ctx.accounts.trade.payment.is_some() && !ctx.accounts.trade.payment_escrowed)
         {
             return err!(Errors::ConfirmTradeError);
         }
 
-        ctx.accounts.creator_vault.owner = ctx.accounts.trade.acceptor_key.unwrap().key();
-        ctx.accounts.acceptor_vault.owner = ctx.accounts.trade.creator_key.key();
+        //3. Remaining accounts: creator vaults, acceptor vaults, creator manifests, acceptor manifests,
+        //   then royalty accounts for the creator's NFTs when the trade has a payment
+        let trade_key = ctx.accounts.trade.key();
+        let creator_key = ctx.accounts.trade.creator_key;
+        let acceptor_key = ctx
+            .accounts
+            .trade
+            .acceptor_key
+            .ok_or(Errors::ConfirmTradeError)?;
+        let creator_vault_keys = ctx.accounts.trade.creator_vault_keys.clone();
+        let acceptor_vault_keys = ctx.accounts.trade.acceptor_vault_keys.clone();
+        let vault_count = creator_vault_keys.len() + acceptor_vault_keys.len();
+        if ctx.remaining_accounts.len() < vault_count * 2 {
+            return err!(Errors::TradeAccountsMismatch);
+        }
+        let (trade_infos, royalty_infos) = ctx.remaining_accounts.split_at(vault_count * 2);
+        let (vault_infos, manifest_infos) = trade_infos.split_at(vault_count);
+        let (creator_vault_infos, acceptor_vault_infos) =
+            vault_infos.split_at(creator_vault_keys.len());
+        let (creator_manifest_infos, acceptor_manifest_infos) =
+            manifest_infos.split_at(creator_vault_keys.len());
+
+        //4. No vault changed since the other side locked against it
+        if ctx.accounts.trade.creator_terms
+            != manifest_terms(&acceptor_vault_keys, acceptor_manifest_infos)?
+            || ctx.accounts.trade.acceptor_terms
+                != manifest_terms(&creator_vault_keys, creator_manifest_infos)?
+        {
+            return err!(Errors::TradeTermsChanged);
+        }
+
+        // Settle: hand every vault over unfrozen so the new owners can withdraw,
+        // the trade account itself is closed back to the creator
+        release_trade_vaults(
+            &creator_vault_keys,
+            creator_vault_infos,
+            Some(acceptor_key),
+            trade_key,
+        )?;
+        release_trade_vaults(
+            &acceptor_vault_keys,
+            acceptor_vault_infos,
+            Some(creator_key),
+            trade_key,
+        )?;
+        if ctx.accounts.trade.payment_escrowed {
+            // The payment buys the creator's vaults, so royalties are owed on their NFTs
+            let mut nft_mints = vec![];
+            for manifest_info in creator_manifest_infos {
+                let manifest: Account<VaultManifest> = Account::try_from(manifest_info)?;
+                nft_mints.extend(manifest.royalty_mints());
+            }
+            release_payment(
+                &ctx.accounts.trade,
+                &ctx.accounts.creator.to_account_info(),
+                ctx.accounts.payment_escrow.as_ref(),
+                ctx.accounts.creator_token_account.as_ref(),
+                ctx.accounts.token_program.as_ref(),
+                Some(SaleFees {
+                    config: &ctx.accounts.config,
+                    fee_bps: ctx.accounts.config.fees.trade_bps,
+                    fee_receiver: &ctx.accounts.fee_receiver.to_account_info(),
+                    nft_mints,
+                    royalty_accounts: royalty_infos,
+                }),
+            )?;
+        }
         Ok(())
     }
-}
 
-pub fn assert_decode_metadata<'info>(
-    nft_mint: &Account<'info, Mint>,
-    metadata_account: &AccountInfo<'info>,
-) -> Result<Metadata> {
-    let (key, _) = Pubkey::find_program_address(
-        &[
-            mpl_token_metadata::state::PREFIX.as_bytes(),
-            mpl_token_metadata::id().as_ref(),
-            nft_mint.key().as_ref(),
-        ],
-        &mpl_token_metadata::id(),
-    );
-    if key != *metadata_account.key {
-        require!(true, Errors::BadMetadata);
-    }
-    // Check account owner (redundant because of find_program_address above, but why not).
-    if *metadata_account.owner != mpl_token_metadata::id() {
-        return Err(error!(Errors::BadMetadata));
+    // Expire Trade
+    // Permissionless crank: any live state, the trade is closed once the deadline passed
+    pub fn expire_trade<'info>(ctx: Context<'_, '_, '_, 'info, ExpireTrade<'info>>) -> Result<()> {
+        if !ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeNotExpired);
+        }
+
+        let trade_key = ctx.accounts.trade.key();
+        let vault_keys = ctx.accounts.trade.all_vault_keys();
+        release_trade_vaults(&vault_keys, ctx.remaining_accounts, None, trade_key)?;
+
+        if ctx.accounts.trade.payment_escrowed {
+            let acceptor = ctx
+                .accounts
+                .acceptor
+                .as_ref()
+                .ok_or(Errors::PaymentAccountsMissing)?;
+            if Some(acceptor.key()) != ctx.accounts.trade.acceptor_key {
+                return err!(Errors::PaymentAccountsMismatch);
+            }
+            release_payment(
+                &ctx.accounts.trade,
+                &acceptor.to_account_info(),
+                ctx.accounts.payment_escrow.as_ref(),
+                ctx.accounts.acceptor_token_account.as_ref(),
+                ctx.accounts.token_program.as_ref(),
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Create Multi-party Trade
+    // Names every participant up front, routes[i] is who receives participant i's vault
+    pub fn create_multi_trade(
+        ctx: Context<CreateMultiTrade>,
+        trade_id: u64,
+        participants: Vec<Pubkey>,
+        routes: Vec<u8>,
+        expires_at: Option<i64>,
+    ) -> Result<()> {
+        let count = participants.len();
+        let has_duplicates = participants
+            .iter()
+            .enumerate()
+            .any(|(i, key)| participants[i + 1..].contains(key));
+        if !(3..=MAX_PARTICIPANTS).contains(&count)
+            || !participants.contains(&ctx.accounts.payer.key())
+            || has_duplicates
+        {
+            return err!(Errors::InvalidParticipants);
+        }
+
+        if routes.len() != count
+            || routes
+                .iter()
+                .enumerate()
+                .any(|(i, receiver)| *receiver as usize >= count || *receiver as usize == i)
+        {
+            return err!(Errors::InvalidRoutes);
+        }
+
+        if let Some(expires_at) = expires_at {
+            if expires_at <= Clock::get()?.unix_timestamp {
+                return err!(Errors::InvalidExpiry);
+            }
+        }
+
+        ctx.accounts.trade.trade_id = trade_id;
+        ctx.accounts.trade.creator_key = ctx.accounts.payer.key();
+        ctx.accounts.trade.bump = *ctx.bumps.get("trade").unwrap();
+        ctx.accounts.trade.participants = participants
+            .into_iter()
+            .zip(routes)
+            .map(|(key, receiver)| Participant {
+                key,
+                vault_key: None,
+                receiver,
+                confirmed: false,
+                terms: [0; 32],
+            })
+            .collect();
+        ctx.accounts.trade.status = TradeStatus::Joined;
+        ctx.accounts.trade.expires_at = expires_at;
+        Ok(())
+    }
+
+    // Attach vault to multi-party trade
+    // Joined/Attached -> Attached once every participant has a vault in
+    pub fn attach_vault_to_multi_trade(ctx: Context<AttachMultiVault>) -> Result<()> {
+        if !matches!(
+            ctx.accounts.trade.status,
+            TradeStatus::Joined | TradeStatus::Attached
+        ) {
+            return err!(Errors::TradeNotAttachable);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+
+        let trade_key = ctx.accounts.trade.key();
+        // The vault goes in as its owner's, so a delegate can't attach it for another participant
+        let index = ctx
+            .accounts
+            .trade
+            .participant_index(&ctx.accounts.vault.owner)
+            .ok_or(Errors::NotAParticipant)?;
+        // Can't change out vaults if you've confirmed
+        if ctx.accounts.trade.participants[index].confirmed {
+            return err!(Errors::VaultAttachError);
+        }
+
+        let previous_vault_key = ctx.accounts.trade.participants[index].vault_key;
+        ctx.accounts.trade.participants[index].vault_key = Some(ctx.accounts.vault.key());
+        // Changing out a vault resets everyone's confirmation
+        for participant in ctx.accounts.trade.participants.iter_mut() {
+            participant.confirmed = false;
+        }
+
+        if previous_vault_key != Some(ctx.accounts.vault.key()) {
+            release_attached_vault(
+                previous_vault_key,
+                ctx.accounts.replaced_vault.as_mut(),
+                trade_key,
+            )?;
+        }
+        ctx.accounts.vault.freeze_for(trade_key);
+
+        ctx.accounts.trade.refresh_status();
+        Ok(())
+    }
+
+    // Lock multi-party trade
+    // Pins every attached vault's contents, Attached -> Locked once every participant has locked
+    pub fn lock_multi_trade<'info>(
+        ctx: Context<'_, '_, '_, 'info, LockMultiTrade<'info>>,
+    ) -> Result<()> {
+        if ctx.accounts.trade.status != TradeStatus::Attached {
+            return err!(Errors::TradeNotLockable);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+
+        let (party, delegated_vault) = trade_party(
+            ctx.accounts.payer.key(),
+            ctx.accounts.vault.as_ref(),
+            ctx.accounts.vault_delegate.as_deref(),
+        )?;
+        let index = ctx
+            .accounts
+            .trade
+            .participant_index(&party)
+            .ok_or(Errors::NotAParticipant)?;
+        if delegated_vault.is_some()
+            && ctx.accounts.trade.participants[index].vault_key != delegated_vault
+        {
+            return err!(Errors::VaultTradeMismatch);
+        }
+        let terms = manifest_terms(&ctx.accounts.trade.vault_keys(), ctx.remaining_accounts)?;
+
+        ctx.accounts.trade.participants[index].terms = terms;
+        ctx.accounts.trade.participants[index].confirmed = true;
+        ctx.accounts.trade.refresh_status();
+        Ok(())
+    }
+
+    // Confirm multi-party trade
+    // Locked, every vault goes to the participant the routing table names (trade is closed)
+    pub fn confirm_multi_trade<'info>(
+        ctx: Context<'_, '_, '_, 'info, ConfirmMultiTrade<'info>>,
+    ) -> Result<()> {
+        if ctx.accounts.trade.status != TradeStatus::Locked {
+            return err!(Errors::TradeNotConfirmable);
+        }
+        if ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp) {
+            return err!(Errors::TradeExpired);
+        }
+        if ctx
+            .accounts
+            .trade
+            .participant_index(&ctx.accounts.payer.key())
+            .is_none()
+        {
+            return err!(Errors::NotAParticipant);
+        }
+
+        let trade_key = ctx.accounts.trade.key();
+        let participants = ctx.accounts.trade.participants.clone();
+        if ctx.remaining_accounts.len() != participants.len() * 2 {
+            return err!(Errors::TradeAccountsMismatch);
+        }
+        let (vault_infos, manifest_infos) = ctx.remaining_accounts.split_at(participants.len());
+
+        let terms = manifest_terms(&ctx.accounts.trade.vault_keys(), manifest_infos)?;
+        if participants.iter().any(|p| p.terms != terms) {
+            return err!(Errors::TradeTermsChanged);
+        }
+
+        for (participant, vault_info) in participants.iter().zip(vault_infos) {
+            let vault_key = participant.vault_key.ok_or(Errors::TradeAccountsMismatch)?;
+            let mut vault = load_trade_vault(vault_info, vault_key)?;
+            vault.owner = participants[participant.receiver as usize].key;
+            vault.release_from(trade_key)?;
+            vault.exit(&crate::ID)?;
+        }
+        Ok(())
+    }
+
+    // Cancel multi-party trade
+    // Any participant can walk away, anyone can unwind it after expiry (trade is closed)
+    pub fn cancel_multi_trade<'info>(
+        ctx: Context<'_, '_, '_, 'info, CancelMultiTrade<'info>>,
+    ) -> Result<()> {
+        let expired = ctx.accounts.trade.is_expired(Clock::get()?.unix_timestamp);
+        if !expired
+            && ctx
+                .accounts
+                .trade
+                .participant_index(&ctx.accounts.payer.key())
+                .is_none()
+        {
+            return err!(Errors::NotAParticipant);
+        }
+
+        let trade_key = ctx.accounts.trade.key();
+        let vault_keys = ctx.accounts.trade.vault_keys();
+        release_trade_vaults(&vault_keys, ctx.remaining_accounts, None, trade_key)?;
+        Ok(())
+    }
+
+    // List Vault
+    // Freezes the vault for sale at a fixed price in SOL or an SPL mint
+    pub fn list_vault(
+        ctx: Context<ListVault>,
+        price: u64,
+        payment_mint: Option<Pubkey>,
+    ) -> Result<()> {
+        if price == 0 {
+            return err!(Errors::InvalidPrice);
+        }
+
+        ctx.accounts.listing.vault = ctx.accounts.vault.key();
+        ctx.accounts.listing.seller = ctx.accounts.payer.key();
+        ctx.accounts.listing.payment_mint = payment_mint;
+        ctx.accounts.listing.price = price;
+        ctx.accounts.listing.bump = *ctx.bumps.get("listing").unwrap();
+
+        let listing_key = ctx.accounts.listing.key();
+        ctx.accounts.vault.freeze_for(listing_key);
+        Ok(())
+    }
+
+    // Update Listing
+    pub fn update_listing(
+        ctx: Context<UpdateListing>,
+        price: u64,
+        payment_mint: Option<Pubkey>,
+    ) -> Result<()> {
+        if price == 0 {
+            return err!(Errors::InvalidPrice);
+        }
+
+        ctx.accounts.listing.payment_mint = payment_mint;
+        ctx.accounts.listing.price = price;
+        Ok(())
+    }
+
+    // Delist Vault
+    // Unfreezes the vault, the listing is closed back to the seller
+    pub fn delist_vault(ctx: Context<DelistVault>) -> Result<()> {
+        let listing_key = ctx.accounts.listing.key();
+        ctx.accounts.vault.release_from(listing_key)?;
+        Ok(())
+    }
+
+    // Buy Vault
+    // Pays the seller and hands the vault to the buyer unfrozen, the listing is closed back to the seller
+    // max_price and expected_payment_mint guard against the seller changing the terms while the buy is in flight
+    pub fn buy_vault<'info>(
+        ctx: Context<'_, '_, '_, 'info, BuyVault<'info>>,
+        max_price: u64,
+        expected_payment_mint: Option<Pubkey>,
+    ) -> Result<()> {
+        let price = ctx.accounts.listing.price;
+        if price > max_price {
+            return err!(Errors::ListingPriceChanged);
+        }
+        if ctx.accounts.listing.payment_mint != expected_payment_mint {
+            return err!(Errors::ListingPaymentMintChanged);
+        }
+
+        let fees = SaleFees {
+            config: &ctx.accounts.config,
+            fee_bps: ctx.accounts.config.fees.listing_bps,
+            fee_receiver: &ctx.accounts.fee_receiver.to_account_info(),
+            nft_mints: ctx.accounts.manifest.royalty_mints(),
+            royalty_accounts: ctx.remaining_accounts,
+        };
+        match ctx.accounts.listing.payment_mint {
+            None => distribute_payment(
+                &PaymentSource::Signer {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                },
+                None,
+                price,
+                &ctx.accounts.seller.to_account_info(),
+                fees,
+            )?,
+            Some(mint) => {
+                let (payer_token_account, seller_token_account, token_program) = match (
+                    ctx.accounts.payer_token_account.as_ref(),
+                    ctx.accounts.seller_token_account.as_ref(),
+                    ctx.accounts.token_program.as_ref(),
+                ) {
+                    (Some(from), Some(to), Some(program)) => (from, to, program),
+                    _ => return err!(Errors::PaymentAccountsMissing),
+                };
+                if payer_token_account.mint != mint
+                    || seller_token_account.mint != mint
+                    || seller_token_account.owner != ctx.accounts.seller.key()
+                {
+                    return err!(Errors::PaymentAccountsMismatch);
+                }
+
+                distribute_payment(
+                    &PaymentSource::Token {
+                        from: payer_token_account.to_account_info(),
+                        authority: ctx.accounts.payer.to_account_info(),
+                        token_program: token_program.to_account_info(),
+                        signer_seeds: &[],
+                    },
+                    Some(mint),
+                    price,
+                    &seller_token_account.to_account_info(),
+                    fees,
+                )?;
+            }
+        }
+
+        let listing_key = ctx.accounts.listing.key();
+        ctx.accounts.vault.owner = ctx.accounts.payer.key();
+        ctx.accounts.vault.release_from(listing_key)?;
+        Ok(())
+    }
+
+    // Create Auction
+    // Freezes the vault until the auction is settled or cancelled
+    pub fn create_auction(
+        ctx: Context<CreateAuction>,
+        reserve_price: u64,
+        min_increment: u64,
+        end_time: i64,
+    ) -> Result<()> {
+        if end_time <= Clock::get()?.unix_timestamp || min_increment == 0 {
+            return err!(Errors::InvalidAuction);
+        }
+
+        ctx.accounts.auction.vault = ctx.accounts.vault.key();
+        ctx.accounts.auction.seller = ctx.accounts.payer.key();
+        ctx.accounts.auction.reserve_price = reserve_price;
+        ctx.accounts.auction.min_increment = min_increment;
+        ctx.accounts.auction.end_time = end_time;
+        ctx.accounts.auction.highest_bid = 0;
+        ctx.accounts.auction.highest_bidder = None;
+        ctx.accounts.auction.bump = *ctx.bumps.get("auction").unwrap();
+
+        let auction_key = ctx.accounts.auction.key();
+        ctx.accounts.vault.freeze_for(auction_key);
+        Ok(())
+    }
+
+    // Place Bid
+    // Escrows the bid in the auction account and refunds whoever was outbid
+    pub fn place_bid(ctx: Context<PlaceBid>, amount: u64) -> Result<()> {
+        if Clock::get()?.unix_timestamp >= ctx.accounts.auction.end_time {
+            return err!(Errors::AuctionEnded);
+        }
+
+        let minimum_bid = match ctx.accounts.auction.highest_bidder {
+            None => ctx.accounts.auction.reserve_price,
+            Some(_) => ctx
+                .accounts
+                .auction
+                .highest_bid
+                .checked_add(ctx.accounts.auction.min_increment)
+                .ok_or(Errors::BidTooLow)?,
+        };
+        if amount < minimum_bid {
+            return err!(Errors::BidTooLow);
+        }
+
+        invoke(
+            &transfer(
+                &ctx.accounts.payer.key(),
+                &ctx.accounts.auction.key(),
+                amount,
+            ),
+            &[
+                ctx.accounts.payer.to_account_info(),
+                ctx.accounts.auction.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        if let Some(highest_bidder) = ctx.accounts.auction.highest_bidder {
+            let previous_bidder = ctx
+                .accounts
+                .previous_bidder
+                .as_ref()
+                .ok_or(Errors::PaymentAccountsMissing)?;
+            if previous_bidder.key() != highest_bidder {
+                return err!(Errors::PaymentAccountsMismatch);
+            }
+
+            // The auction account is owned by this program, so lamports can be moved directly
+            let refund = ctx.accounts.auction.highest_bid;
+            **ctx
+                .accounts
+                .auction
+                .to_account_info()
+                .try_borrow_mut_lamports()? -= refund;
+            **previous_bidder.try_borrow_mut_lamports()? += refund;
+        }
+
+        ctx.accounts.auction.highest_bid = amount;
+        ctx.accounts.auction.highest_bidder = Some(ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    // Settle Auction
+    // Permissionless once ended: the winner gets the vault, the seller gets the winning bid
+    // less fees. Remaining accounts carry the royalty accounts for the vault's NFTs.
+    // Without bids the vault just goes back to the seller unfrozen.
+    pub fn settle_auction<'info>(
+        ctx: Context<'_, '_, '_, 'info, SettleAuction<'info>>,
+    ) -> Result<()> {
+        if Clock::get()?.unix_timestamp < ctx.accounts.auction.end_time {
+            return err!(Errors::AuctionNotEnded);
+        }
+
+        if let Some(winner) = ctx.accounts.auction.highest_bidder {
+            distribute_payment(
+                &PaymentSource::Escrow(ctx.accounts.auction.to_account_info()),
+                None,
+                ctx.accounts.auction.highest_bid,
+                &ctx.accounts.seller.to_account_info(),
+                SaleFees {
+                    config: &ctx.accounts.config,
+                    fee_bps: ctx.accounts.config.fees.auction_bps,
+                    fee_receiver: &ctx.accounts.fee_receiver.to_account_info(),
+                    nft_mints: ctx.accounts.manifest.royalty_mints(),
+                    royalty_accounts: ctx.remaining_accounts,
+                },
+            )?;
+
+            ctx.accounts.vault.owner = winner;
+        }
+
+        let auction_key = ctx.accounts.auction.key();
+        ctx.accounts.vault.release_from(auction_key)?;
+        Ok(())
+    }
+
+    // Cancel Auction
+    // Only before anyone has bid, the auction is closed back to the seller
+    pub fn cancel_auction(ctx: Context<CancelAuction>) -> Result<()> {
+        if ctx.accounts.auction.highest_bidder.is_some() {
+            return err!(Errors::AuctionHasBids);
+        }
+
+        let auction_key = ctx.accounts.auction.key();
+        ctx.accounts.vault.release_from(auction_key)?;
+        Ok(())
+    }
+
+    // Make Collection Offer
+    // Escrows the price for any NFT from a verified collection, filled into the buyer's vault
+    pub fn make_collection_offer(
+        ctx: Context<MakeCollectionOffer>,
+        offer_id: u64,
+        collection: Pubkey,
+        price: u64,
+    ) -> Result<()> {
+        if price == 0 {
+            return err!(Errors::InvalidPrice);
+        }
+
+        ctx.accounts.offer.buyer = ctx.accounts.payer.key();
+        ctx.accounts.offer.offer_id = offer_id;
+        ctx.accounts.offer.vault = ctx.accounts.vault.key();
+        ctx.accounts.offer.collection = collection;
+        ctx.accounts.offer.price = price;
+        ctx.accounts.offer.bump = *ctx.bumps.get("offer").unwrap();
+
+        invoke(
+            &transfer(&ctx.accounts.payer.key(), &ctx.accounts.offer.key(), price),
+            &[
+                ctx.accounts.payer.to_account_info(),
+                ctx.accounts.offer.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Cancel Collection Offer
+    // Closing the offer refunds the escrowed price along with the rent
+    pub fn cancel_collection_offer(_ctx: Context<CancelCollectionOffer>) -> Result<()> {
+        Ok(())
+    }
+
+    // Accept Collection Offer (Token Program or MPL Token Program)
+    // The holder's NFT/pNFT goes into the buyer's vault and the holder is paid the offer price
+    pub fn accept_collection_offer<'info>(
+        ctx: Context<'_, '_, '_, 'info, AcceptCollectionOffer<'info>>,
+        authorization_data: Option<AuthorizationDataLocal>,
+        rules_acc_present: bool,
+    ) -> Result<()> {
+        let metadata = assert_decode_metadata(
+            &ctx.accounts.nft_mint,
+            &ctx.accounts.metadata.to_account_info(),
+        )?;
+        match &metadata.collection {
+            Some(collection)
+                if collection.verified && collection.key == ctx.accounts.offer.collection => {}
+            _ => return err!(Errors::CollectionMismatch),
+        }
+
+        // Remaining accounts: the auth rules account if present, then the royalty accounts for this NFT
+        let (rules_infos, royalty_infos) = ctx
+            .remaining_accounts
+            .split_at(usize::from(rules_acc_present).min(ctx.remaining_accounts.len()));
+
+        let kind = if metadata.token_standard == Some(TokenStandard::ProgrammableNonFungible) {
+            transfer_pnft(
+                PNftTransfer {
+                    source_ata: ctx.accounts.payer_ata.to_account_info(),
+                    source_owner: ctx.accounts.payer.to_account_info(),
+                    destination_ata: ctx.accounts.vault_ata.to_account_info(),
+                    destination_owner: ctx.accounts.vault.to_account_info(),
+                    nft_mint: ctx.accounts.nft_mint.to_account_info(),
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    edition: ctx.accounts.edition.to_account_info(),
+                    owner_token_record: ctx.accounts.owner_token_record.to_account_info(),
+                    destination_token_record: ctx.accounts.vault_token_record.to_account_info(),
+                    payer: ctx.accounts.payer.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    sysvar_instructions: ctx.accounts.sysvar_instructions.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                    associated_token_program: ctx
+                        .accounts
+                        .associated_token_program
+                        .to_account_info(),
+                    auth_rules_program: ctx.accounts.auth_rules_program.to_account_info(),
+                    auth_rules: rules_infos.first().cloned(),
+                },
+                &metadata,
+                authorization_data,
+                &[],
+            )?;
+            AssetKind::PNft
+        } else {
+            // The vault may not hold this mint yet
+            create_idempotent(CpiContext::new(
+                ctx.accounts.associated_token_program.to_account_info(),
+                Create {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    associated_token: ctx.accounts.vault_ata.to_account_info(),
+                    authority: ctx.accounts.vault.to_account_info(),
+                    mint: ctx.accounts.nft_mint.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+            ))?;
+
+            transferSPL(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferNFT {
+                        from: ctx.accounts.payer_ata.to_account_info(),
+                        to: ctx.accounts.vault_ata.to_account_info(),
+                        authority: ctx.accounts.payer.to_account_info(),
+                    },
+                ),
+                1,
+            )?;
+            AssetKind::Nft
+        };
+
+        ctx.accounts
+            .manifest
+            .add(kind, ctx.accounts.nft_mint.key(), 1)?;
+
+        distribute_payment(
+            &PaymentSource::Escrow(ctx.accounts.offer.to_account_info()),
+            None,
+            ctx.accounts.offer.price,
+            &ctx.accounts.payer.to_account_info(),
+            SaleFees {
+                config: &ctx.accounts.config,
+                fee_bps: ctx.accounts.config.fees.offer_bps,
+                fee_receiver: &ctx.accounts.fee_receiver.to_account_info(),
+                nft_mints: vec![ctx.accounts.nft_mint.key()],
+                royalty_accounts: royalty_infos,
+            },
+        )
+    }
+
+    // Accept Collection Offer (MPL Bubblegum Program)
+    // The leaf's data and creator hashes are recomputed from the given metadata,
+    // so Bubblegum only accepts the transfer if the collection claim is genuine
+    pub fn accept_collection_offer_cnft<'info>(
+        ctx: Context<'_, '_, '_, 'info, AcceptCollectionOfferCNFT<'info>>,
+        root: [u8; 32],
+        metadata: MetadataArgs,
+        nonce: u64,
+        index: u32,
+    ) -> Result<()> {
+        match &metadata.collection {
+            Some(collection)
+                if collection.verified && collection.key == ctx.accounts.offer.collection => {}
+            _ => return err!(Errors::CollectionMismatch),
+        }
+        let data_hash = hash_metadata(&metadata)?;
+        let creator_hash = hash_creators(&metadata.creators)?;
+
+        transfer_cnft(
+            CNftTransfer {
+                bubblegum_program: ctx.accounts.bubblegum_program.to_account_info(),
+                tree_authority: ctx.accounts.tree_authority.to_account_info(),
+                leaf_owner: ctx.accounts.payer.to_account_info(),
+                new_leaf_owner: ctx.accounts.vault.to_account_info(),
+                merkle_tree: ctx.accounts.merkle_tree.to_account_info(),
+                log_wrapper: ctx.accounts.log_wrapper.to_account_info(),
+                compression_program: ctx.accounts.compression_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+                proof: ctx.remaining_accounts,
+            },
+            root,
+            data_hash,
+            creator_hash,
+            nonce,
+            index,
+            &[],
+        )?;
+
+        ctx.accounts.manifest.add(
+            AssetKind::CNft,
+            get_asset_id(&ctx.accounts.merkle_tree.key(), nonce),
+            1,
+        )?;
+
+        // cNFT metadata lives off-chain, so only the protocol fee applies
+        distribute_payment(
+            &PaymentSource::Escrow(ctx.accounts.offer.to_account_info()),
+            None,
+            ctx.accounts.offer.price,
+            &ctx.accounts.payer.to_account_info(),
+            SaleFees {
+                config: &ctx.accounts.config,
+                fee_bps: ctx.accounts.config.fees.offer_bps,
+                fee_receiver: &ctx.accounts.fee_receiver.to_account_info(),
+                nft_mints: vec![],
+                royalty_accounts: &[],
+            },
+        )
+    }
+
+    // Initialize Config
+    // One-time setup of the global config, gated on the program's upgrade authority
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        admin: Pubkey,
+        fee_receiver: Pubkey,
+        fees: FeeSchedule,
+        royalties_enforced: bool,
+        asset_programs: AssetPrograms,
+    ) -> Result<()> {
+        if !fees.is_valid() {
+            return err!(Errors::InvalidFee);
+        }
+
+        ctx.accounts.config.admin = admin;
+        ctx.accounts.config.paused = false;
+        ctx.accounts.config.fee_receiver = fee_receiver;
+        ctx.accounts.config.fees = fees;
+        ctx.accounts.config.royalties_enforced = royalties_enforced;
+        ctx.accounts.config.asset_programs = asset_programs;
+        ctx.accounts.config.bump = *ctx.bumps.get("config").unwrap();
+        Ok(())
+    }
+
+    // Update Config
+    // Admin only, can also hand the admin role over
+    pub fn update_config(
+        ctx: Context<UpdateConfig>,
+        admin: Pubkey,
+        fee_receiver: Pubkey,
+        fees: FeeSchedule,
+        royalties_enforced: bool,
+        asset_programs: AssetPrograms,
+    ) -> Result<()> {
+        if !fees.is_valid() {
+            return err!(Errors::InvalidFee);
+        }
+
+        ctx.accounts.config.admin = admin;
+        ctx.accounts.config.fee_receiver = fee_receiver;
+        ctx.accounts.config.fees = fees;
+        ctx.accounts.config.royalties_enforced = royalties_enforced;
+        ctx.accounts.config.asset_programs = asset_programs;
+        Ok(())
+    }
+
+    // Set Paused
+    // Admin kill switch, halts every other instruction without a redeploy
+    pub fn set_paused(ctx: Context<UpdateConfig>, paused: bool) -> Result<()> {
+        ctx.accounts.config.paused = paused;
+        Ok(())
+    }
+}
+
+/// Unfreezes the vault a trade side had attached, if any.
+/// The passed vault must be the one recorded on the trade.
+pub fn release_attached_vault(
+    attached_vault_key: Option<Pubkey>,
+    vault: Option<&mut Account<Vault>>,
+    trade_key: Pubkey,
+) -> Result<()> {
+    if let Some(attached_vault_key) = attached_vault_key {
+        let vault = vault.ok_or(Errors::VaultTradeMismatch)?;
+        if vault.key() != attached_vault_key {
+            return err!(Errors::VaultTradeMismatch);
+        }
+        vault.release_from(trade_key)?;
+    }
+    Ok(())
+}
+
+pub fn assert_decode_metadata<'info>(
+    nft_mint: &impl Key,
+    metadata_account: &AccountInfo<'info>,
+) -> Result<Metadata> {
+    let (key, _) = Pubkey::find_program_address(
+        &[
+            mpl_token_metadata::state::PREFIX.as_bytes(),
+            mpl_token_metadata::id().as_ref(),
+            nft_mint.key().as_ref(),
+        ],
+        &mpl_token_metadata::id(),
+    );
+    if key != *metadata_account.key {
+        return Err(error!(Errors::BadMetadata));
+    }
+    // Check account owner (redundant because of find_program_address above, but why not).
+    if *metadata_account.owner != mpl_token_metadata::id() {
+        return Err(error!(Errors::BadMetadata));
     }
 
     Ok(Metadata::from_account_info(metadata_account)?)
 }
+
+/// Fee Token-2022 withholds when transferring `amount`, zero for mints without the transfer-fee extension
+pub fn transfer_fee<'info>(
+    mint: &InterfaceAccount<'info, InterfaceMint>,
+    amount: u64,
+) -> Result<u64> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != anchor_spl::token_2022::ID {
+        return Ok(0);
+    }
+
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<MintState>::unpack(&mint_data)?;
+    match mint_state.get_extension::<TransferFeeConfig>() {
+        Ok(fee_config) => Ok(fee_config
+            .calculate_epoch_fee(Clock::get()?.epoch, amount)
+            .ok_or(Errors::TransferFeeError)?),
+        Err(_) => Ok(0),
+    }
+}
+
+/// `transfer_checked` out of a vault, resolving the extra accounts of a Token-2022 transfer hook
+/// from `hook_accounts` (the hook's validation account, the hook program and whatever it lists)
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_checked_with_hook<'info>(
+    token_program: &AccountInfo<'info>,
+    from: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    to: AccountInfo<'info>,
+    authority: AccountInfo<'info>,
+    hook_accounts: &[AccountInfo<'info>],
+    amount: u64,
+    decimals: u8,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let mut transfer_ix = spl_token_2022::instruction::transfer_checked(
+        token_program.key,
+        from.key,
+        mint.key,
+        to.key,
+        authority.key,
+        &[],
+        amount,
+        decimals,
+    )?;
+    let mut account_infos = vec![from, mint.clone(), to, authority, token_program.clone()];
+
+    if *mint.owner == anchor_spl::token_2022::ID {
+        let hook_program = {
+            let mint_data = mint.try_borrow_data()?;
+            let mint_state = StateWithExtensions::<MintState>::unpack(&mint_data)?;
+            transfer_hook::get_program_id(&mint_state)
+        };
+        if let Some(hook_program) = hook_program {
+            add_cpi_accounts_for_execute(
+                &mut transfer_ix,
+                &mut account_infos,
+                mint.key,
+                &hook_program,
+                hook_accounts,
+            )
+            .map_err(|_| error!(Errors::TransferHookAccountsMismatch))?;
+        }
+    }
+
+    invoke_signed(&transfer_ix, &account_infos, signer_seeds)?;
+    Ok(())
+}
+
+/// Whichever of the token programs were passed, for closing token accounts of either
+pub fn token_program_infos<'info>(
+    token_program: Option<&Program<'info, Token>>,
+    token_2022_program: Option<&Program<'info, Token2022>>,
+) -> Vec<AccountInfo<'info>> {
+    [
+        token_program.map(|p| p.to_account_info()),
+        token_2022_program.map(|p| p.to_account_info()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Closes empty token accounts owned by the vault, with the vault signing, and sends their rent
+/// to `destination`. Each account is closed through whichever of `token_programs` owns it.
+pub fn close_empty_token_accounts<'info>(
+    vault: &Account<'info, Vault>,
+    token_accounts: &[AccountInfo<'info>],
+    token_programs: &[AccountInfo<'info>],
+    destination: &AccountInfo<'info>,
+) -> Result<()> {
+    for token_account_info in token_accounts {
+        let token_account: InterfaceAccount<token_interface::TokenAccount> =
+            InterfaceAccount::try_from(token_account_info)?;
+        if token_account.owner != vault.key() {
+            return err!(Errors::NotVaultTokenAccount);
+        }
+        if token_account.amount != 0 {
+            return err!(Errors::VaultNotEmpty);
+        }
+        let token_program = token_programs
+            .iter()
+            .find(|program| program.key() == *token_account_info.owner)
+            .ok_or(Errors::NotVaultTokenAccount)?;
+
+        vault.with_signer_seeds(|signers_seeds| {
+            token_interface::close_account(CpiContext::new_with_signer(
+                token_program.clone(),
+                token_interface::CloseAccount {
+                    account: token_account_info.clone(),
+                    destination: destination.clone(),
+                    authority: vault.to_account_info(),
+                },
+                signers_seeds,
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Creates the receiver's ATA when the associated token program is passed, then checks
+/// that the receiver token account holds the withdrawn mint and belongs to the receiver
+pub fn prepare_receiver_ata<'info>(
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    receiver: &UncheckedAccount<'info>,
+    receiver_ata: &UncheckedAccount<'info>,
+    mint: &InterfaceAccount<'info, InterfaceMint>,
+    token_program: &Interface<'info, TokenInterface>,
+    associated_token_program: Option<&Program<'info, AssociatedToken>>,
+) -> Result<()> {
+    if let Some(associated_token_program) = associated_token_program {
+        create_idempotent(CpiContext::new(
+            associated_token_program.to_account_info(),
+            Create {
+                payer: payer.to_account_info(),
+                associated_token: receiver_ata.to_account_info(),
+                authority: receiver.to_account_info(),
+                mint: mint.to_account_info(),
+                system_program: system_program.to_account_info(),
+                token_program: token_program.to_account_info(),
+            },
+        ))?;
+    }
+
+    check_receiver_ata(
+        &receiver_ata.to_account_info(),
+        &mint.key(),
+        &receiver.key(),
+    )
+}
+
+/// Checks that the receiver token account holds `mint` and belongs to `receiver`
+pub fn check_receiver_ata(
+    receiver_ata: &AccountInfo,
+    mint: &Pubkey,
+    receiver: &Pubkey,
+) -> Result<()> {
+    let receiver_token_account: InterfaceAccount<token_interface::TokenAccount> =
+        InterfaceAccount::try_from(receiver_ata)?;
+    require_keys_eq!(receiver_token_account.mint, *mint, Errors::MintMismatch);
+    require_keys_eq!(
+        receiver_token_account.owner,
+        *receiver,
+        Errors::ReceiverAtaMismatch
+    );
+    Ok(())
+}
+
+/// Accounts of a pNFT transfer through the Token Metadata program, signed by the source owner
+pub struct PNftTransfer<'info> {
+    pub source_ata: AccountInfo<'info>,
+    pub source_owner: AccountInfo<'info>,
+    pub destination_ata: AccountInfo<'info>,
+    pub destination_owner: AccountInfo<'info>,
+    pub nft_mint: AccountInfo<'info>,
+    pub metadata: AccountInfo<'info>,
+    pub edition: AccountInfo<'info>,
+    pub owner_token_record: AccountInfo<'info>,
+    pub destination_token_record: AccountInfo<'info>,
+    pub payer: AccountInfo<'info>,
+    pub system_program: AccountInfo<'info>,
+    pub sysvar_instructions: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+    pub associated_token_program: AccountInfo<'info>,
+    pub auth_rules_program: AccountInfo<'info>,
+    /// Only needed when the pNFT's metadata names a rule set
+    pub auth_rules: Option<AccountInfo<'info>>,
+}
+
+/// Transfers a pNFT, `signers_seeds` are the vault's when it's the source and empty otherwise
+pub fn transfer_pnft(
+    accounts: PNftTransfer,
+    nft_metadata: &Metadata,
+    authorization_data: Option<AuthorizationDataLocal>,
+    signers_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let mut builder = TransferBuilder::new();
+
+    builder
+        .token(accounts.source_ata.key())
+        .token_owner(accounts.source_owner.key())
+        .authority(accounts.source_owner.key())
+        .destination(accounts.destination_ata.key())
+        .destination_owner(accounts.destination_owner.key())
+        .mint(accounts.nft_mint.key())
+        .metadata(accounts.metadata.key())
+        .edition(accounts.edition.key())
+        .payer(accounts.payer.key())
+        .owner_token_record(accounts.owner_token_record.key())
+        .destination_token_record(accounts.destination_token_record.key());
+
+    let mut account_infos = vec![
+        accounts.source_ata,
+        accounts.source_owner,
+        accounts.destination_ata,
+        accounts.destination_owner,
+        accounts.nft_mint,
+        accounts.metadata,
+        accounts.edition,
+        accounts.owner_token_record,
+        accounts.destination_token_record,
+        accounts.payer,
+        accounts.system_program,
+        accounts.sysvar_instructions,
+        accounts.token_program,
+        accounts.associated_token_program,
+        accounts.auth_rules_program.clone(),
+    ];
+
+    // A rule set on the metadata needs the matching rules account in the CPI
+    if let Some(V1 {
+        rule_set: Some(rule_set),
+    }) = nft_metadata.programmable_config
+    {
+        let rules_acc = accounts.auth_rules.ok_or(Errors::BadRuleset)?;
+        require!(rule_set == rules_acc.key(), Errors::BadRuleset);
+
+        builder.authorization_rules_program(accounts.auth_rules_program.key());
+        builder.authorization_rules(rules_acc.key());
+        account_infos.push(rules_acc);
+    }
+
+    let transfer_ix = builder
+        .build(TransferArgs::V1 {
+            amount: 1,
+            authorization_data: authorization_data.map(AuthorizationData::from),
+        })
+        .map_err(|_| Errors::PNftTransferError)?
+        .instruction();
+
+    invoke_signed(&transfer_ix, &account_infos, signers_seeds)?;
+    Ok(())
+}
+
+// first 8 bytes of SHA256("global:transfer")
+const TRANSFER_DISCRIMINATOR: &'static [u8; 8] = &[163, 52, 200, 231, 140, 3, 69, 186];
+
+/// Accounts of a Bubblegum cNFT transfer, signed by the leaf owner
+pub struct CNftTransfer<'a, 'info> {
+    pub bubblegum_program: AccountInfo<'info>,
+    pub tree_authority: AccountInfo<'info>,
+    pub leaf_owner: AccountInfo<'info>,
+    pub new_leaf_owner: AccountInfo<'info>,
+    pub merkle_tree: AccountInfo<'info>,
+    pub log_wrapper: AccountInfo<'info>,
+    pub compression_program: AccountInfo<'info>,
+    pub system_program: AccountInfo<'info>,
+    /// Proof path
+    pub proof: &'a [AccountInfo<'info>],
+}
+
+/// Transfers a cNFT, `signers_seeds` are the vault's when it's the leaf owner and empty otherwise
+pub fn transfer_cnft(
+    accounts: CNftTransfer,
+    root: [u8; 32],
+    data_hash: [u8; 32],
+    creator_hash: [u8; 32],
+    nonce: u64,
+    index: u32,
+    signers_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let mut metas: Vec<AccountMeta> = vec![
+        AccountMeta::new_readonly(accounts.tree_authority.key(), false),
+        AccountMeta::new_readonly(accounts.leaf_owner.key(), true),
+        AccountMeta::new_readonly(accounts.leaf_owner.key(), false),
+        AccountMeta::new_readonly(accounts.new_leaf_owner.key(), false),
+        AccountMeta::new(accounts.merkle_tree.key(), false),
+        AccountMeta::new_readonly(accounts.log_wrapper.key(), false),
+        AccountMeta::new_readonly(accounts.compression_program.key(), false),
+        AccountMeta::new_readonly(accounts.system_program.key(), false),
+    ];
+
+    let mut data: Vec<u8> = vec![];
+    data.extend(TRANSFER_DISCRIMINATOR);
+    data.extend(root);
+    data.extend(data_hash);
+    data.extend(creator_hash);
+    data.extend(nonce.to_le_bytes());
+    data.extend(index.to_le_bytes());
+
+    let mut account_infos: Vec<AccountInfo> = vec![
+        accounts.tree_authority,
+        accounts.leaf_owner.clone(),
+        accounts.leaf_owner,
+        accounts.new_leaf_owner,
+        accounts.merkle_tree,
+        accounts.log_wrapper,
+        accounts.compression_program,
+        accounts.system_program,
+    ];
+
+    for acc in accounts.proof.iter() {
+        metas.push(AccountMeta::new_readonly(acc.key(), false));
+        account_infos.push(acc.to_account_info());
+    }
+
+    invoke_signed(
+        &Instruction {
+            program_id: accounts.bubblegum_program.key(),
+            accounts: metas,
+            data,
+        },
+        account_infos.as_slice(),
+        signers_seeds,
+    )?;
+    Ok(())
+}
+
+/// Runs the transfers of a token batch to `receiver`, `groups` holds [vault_ata, mint, receiver_ata]
+/// per entry of `amounts`. Mints with a transfer hook need the single withdraw instructions.
+pub fn withdraw_token_batch<'info>(
+    vault: &Account<'info, Vault>,
+    receiver: &AccountInfo<'info>,
+    manifest: &mut Account<'info, VaultManifest>,
+    token_program: &AccountInfo<'info>,
+    groups: &[AccountInfo<'info>],
+    amounts: &[u64],
+) -> Result<()> {
+    if groups.len() % 3 != 0 || groups.len() / 3 != amounts.len() {
+        return err!(Errors::MalformedBatch);
+    }
+    if amounts.is_empty() || amounts.len() > MAX_TOKEN_BATCH {
+        return err!(Errors::InvalidBatchSize);
+    }
+
+    vault.with_signer_seeds(|signers_seeds| {
+        for (group, amount) in groups.chunks(3).zip(amounts) {
+            let [vault_ata, mint, receiver_ata] = group else {
+                return err!(Errors::MalformedBatch);
+            };
+            let vault_token_account: InterfaceAccount<token_interface::TokenAccount> =
+                InterfaceAccount::try_from(vault_ata)?;
+            let mint_account: InterfaceAccount<InterfaceMint> = InterfaceAccount::try_from(mint)?;
+            require_keys_eq!(
+                vault_token_account.owner,
+                vault.key(),
+                Errors::NotVaultTokenAccount
+            );
+            require_keys_eq!(vault_token_account.mint, mint.key(), Errors::MintMismatch);
+            check_receiver_ata(receiver_ata, &mint.key(), &receiver.key())?;
+
+            transfer_checked_with_hook(
+                token_program,
+                vault_ata.clone(),
+                mint.clone(),
+                receiver_ata.clone(),
+                vault.to_account_info(),
+                &[],
+                *amount,
+                mint_account.decimals,
+                signers_seeds,
+            )?;
+            manifest.remove(mint.key(), *amount);
+        }
+        Ok(())
+    })
+}
+
+pub fn set_delegate_grant(
+    vault_delegate: &mut VaultDelegate,
+    owner: Pubkey,
+    permissions: u8,
+    expires_at: Option<i64>,
+) -> Result<()> {
+    if permissions == 0 || permissions & !DELEGATE_ALL != 0 {
+        return err!(Errors::InvalidDelegatePermissions);
+    }
+    if let Some(expires_at) = expires_at {
+        if expires_at <= Clock::get()?.unix_timestamp {
+            return err!(Errors::InvalidExpiry);
+        }
+    }
+
+    vault_delegate.owner = owner;
+    vault_delegate.permissions = permissions;
+    vault_delegate.expires_at = expires_at;
+    Ok(())
+}
+
+/// Party a lock acts for: the signer itself, or the owner of `vault` when the signer holds a live
+/// DELEGATE_TRADE grant on it. The vault is returned in the delegated case so the caller can check
+/// it sits on that party's side.
+pub fn trade_party(
+    payer: Pubkey,
+    vault: Option<&Account<Vault>>,
+    vault_delegate: Option<&VaultDelegate>,
+) -> Result<(Pubkey, Option<Pubkey>)> {
+    match (vault, vault_delegate) {
+        (Some(vault), Some(vault_delegate)) if vault.owner != payer => {
+            if !vault.can_manage(vault.key(), payer, Some(vault_delegate), DELEGATE_TRADE) {
+                return err!(Errors::VaultNotOwned);
+            }
+            Ok((vault.owner, Some(vault.key())))
+        }
+        _ => Ok((payer, None)),
+    }
+}
+
+/// Loads a vault passed through remaining accounts, it must be the one the trade recorded
+pub fn load_trade_vault<'info>(
+    vault_info: &AccountInfo<'info>,
+    expected_vault_key: Pubkey,
+) -> Result<Account<'info, Vault>> {
+    if vault_info.key() != expected_vault_key || !vault_info.is_writable {
+        return err!(Errors::TradeAccountsMismatch);
+    }
+    Account::try_from(vault_info)
+}
+
+/// Unfreezes the vaults a trade recorded, passed in the same order through remaining accounts,
+/// handing them to `new_owner` on the way out when set
+pub fn release_trade_vaults<'info>(
+    vault_keys: &[Pubkey],
+    vault_infos: &[AccountInfo<'info>],
+    new_owner: Option<Pubkey>,
+    trade_key: Pubkey,
+) -> Result<()> {
+    if vault_infos.len() != vault_keys.len() {
+        return err!(Errors::TradeAccountsMismatch);
+    }
+
+    for (vault_key, vault_info) in vault_keys.iter().zip(vault_infos) {
+        let mut vault = load_trade_vault(vault_info, *vault_key)?;
+        if let Some(new_owner) = new_owner {
+            vault.owner = new_owner;
+        }
+        vault.release_from(trade_key)?;
+        vault.exit(&crate::ID)?;
+    }
+    Ok(())
+}
+
+/// Hash over the manifests of the given vaults, passed in the same order
+pub fn manifest_terms(vault_keys: &[Pubkey], manifest_infos: &[AccountInfo]) -> Result<[u8; 32]> {
+    if manifest_infos.len() != vault_keys.len() {
+        return err!(Errors::TradeAccountsMismatch);
+    }
+
+    let mut hashes = Vec::with_capacity(manifest_infos.len());
+    for (vault_key, manifest_info) in vault_keys.iter().zip(manifest_infos) {
+        let manifest: Account<VaultManifest> = Account::try_from(manifest_info)?;
+        if manifest.vault != *vault_key {
+            return err!(Errors::TradeAccountsMismatch);
+        }
+        hashes.push(manifest.terms_hash());
+    }
+
+    let hashes: Vec<&[u8]> = hashes.iter().map(|hash| hash.as_ref()).collect();
+    Ok(hashv(&hashes).to_bytes())
+}
+
+/// Moves the acceptor's payment into escrow under the trade account
+pub fn escrow_payment<'info>(
+    trade: &Account<'info, TradeAccount>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    payer_token_account: Option<&Account<'info, TokenAccount>>,
+    payment_escrow: Option<&Account<'info, TokenAccount>>,
+    token_program: Option<&Program<'info, Token>>,
+) -> Result<()> {
+    let payment = trade.payment.ok_or(Errors::InvalidPayment)?;
+
+    match payment.mint {
+        None => invoke(
+            &transfer(&payer.key(), &trade.key(), payment.amount),
+            &[
+                payer.to_account_info(),
+                trade.to_account_info(),
+                system_program.to_account_info(),
+            ],
+        )?,
+        Some(mint) => {
+            let (payer_token_account, payment_escrow, token_program) =
+                match (payer_token_account, payment_escrow, token_program) {
+                    (Some(from), Some(escrow), Some(program)) => (from, escrow, program),
+                    _ => return err!(Errors::PaymentAccountsMissing),
+                };
+            if payment_escrow.owner != trade.key()
+                || payment_escrow.mint != mint
+                || payer_token_account.mint != mint
+            {
+                return err!(Errors::PaymentAccountsMismatch);
+            }
+
+            transferSPL(
+                CpiContext::new(
+                    token_program.to_account_info(),
+                    TransferNFT {
+                        from: payer_token_account.to_account_info(),
+                        to: payment_escrow.to_account_info(),
+                        authority: payer.to_account_info(),
+                    },
+                ),
+                payment.amount,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Pays the escrowed payment out of the trade account to `receiver`.
+/// Settlements pass `fees` to take the protocol fee and royalties, refunds pass None.
+/// An SPL escrow account is closed to the receiver once it's emptied.
+pub fn release_payment<'info>(
+    trade: &Account<'info, TradeAccount>,
+    receiver: &AccountInfo<'info>,
+    payment_escrow: Option<&Account<'info, TokenAccount>>,
+    receiver_token_account: Option<&Account<'info, TokenAccount>>,
+    token_program: Option<&Program<'info, Token>>,
+    fees: Option<SaleFees<'_, 'info>>,
+) -> Result<()> {
+    let payment = trade.payment.ok_or(Errors::InvalidPayment)?;
+
+    match payment.mint {
+        None => {
+            let source = PaymentSource::Escrow(trade.to_account_info());
+            match fees {
+                Some(fees) => distribute_payment(&source, None, payment.amount, receiver, fees)?,
+                None => source.pay(receiver, payment.amount)?,
+            }
+        }
+        Some(mint) => {
+            let (payment_escrow, receiver_token_account, token_program) =
+                match (payment_escrow, receiver_token_account, token_program) {
+                    (Some(escrow), Some(to), Some(program)) => (escrow, to, program),
+                    _ => return err!(Errors::PaymentAccountsMissing),
+                };
+            if payment_escrow.owner != trade.key()
+                || payment_escrow.mint != mint
+                || receiver_token_account.mint != mint
+                || receiver_token_account.owner != receiver.key()
+            {
+                return err!(Errors::PaymentAccountsMismatch);
+            }
+
+            trade.with_signer_seeds(|signers_seeds| {
+                let source = PaymentSource::Token {
+                    from: payment_escrow.to_account_info(),
+                    authority: trade.to_account_info(),
+                    token_program: token_program.to_account_info(),
+                    signer_seeds: signers_seeds,
+                };
+                let to = receiver_token_account.to_account_info();
+                match fees {
+                    Some(fees) => {
+                        distribute_payment(&source, Some(mint), payment.amount, &to, fees)?
+                    }
+                    None => source.pay(&to, payment.amount)?,
+                }
+                close_account(CpiContext::new_with_signer(
+                    token_program.to_account_info(),
+                    CloseAccount {
+                        account: payment_escrow.to_account_info(),
+                        destination: receiver.clone(),
+                        authority: trade.to_account_info(),
+                    },
+                    signers_seeds,
+                ))?;
+                Ok(())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Where the proceeds of a trade or sale are paid from
+pub enum PaymentSource<'a, 'info> {
+    /// Program-owned account holding lamports (trade, auction or offer)
+    Escrow(AccountInfo<'info>),
+    /// Buyer paying lamports through the system program
+    Signer {
+        payer: AccountInfo<'info>,
+        system_program: AccountInfo<'info>,
+    },
+    /// Token account, with the PDA seeds when its authority is one of ours
+    Token {
+        from: AccountInfo<'info>,
+        authority: AccountInfo<'info>,
+        token_program: AccountInfo<'info>,
+        signer_seeds: &'a [&'a [&'a [u8]]],
+    },
+}
+
+impl<'a, 'info> PaymentSource<'a, 'info> {
+    pub fn pay(&self, to: &AccountInfo<'info>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        match self {
+            PaymentSource::Escrow(escrow) => {
+                // Escrows are owned by this program, so lamports can be moved directly
+                **escrow.try_borrow_mut_lamports()? -= amount;
+                **to.try_borrow_mut_lamports()? += amount;
+            }
+            PaymentSource::Signer {
+                payer,
+                system_program,
+            } => invoke(
+                &transfer(payer.key, to.key, amount),
+                &[payer.clone(), to.clone(), system_program.clone()],
+            )?,
+            PaymentSource::Token {
+                from,
+                authority,
+                token_program,
+                signer_seeds,
+            } => transferSPL(
+                CpiContext::new_with_signer(
+                    token_program.clone(),
+                    TransferNFT {
+                        from: from.clone(),
+                        to: to.clone(),
+                        authority: authority.clone(),
+                    },
+                    signer_seeds,
+                ),
+                amount,
+            )?,
+        }
+        Ok(())
+    }
+}
+
+/// Protocol fee and royalties owed on a payment
+pub struct SaleFees<'a, 'info> {
+    pub config: &'a Account<'info, Config>,
+    /// Protocol fee for this venue, from the config's fee schedule
+    pub fee_bps: u16,
+    /// Fee receiver wallet for SOL, its token account for SPL
+    pub fee_receiver: &'a AccountInfo<'info>,
+    /// NFT/pNFT mints the payment buys, royalties are computed on an even share of it per NFT
+    /// Core assets are left out: their royalties plugin isn't enforced on vault-ownership sales
+    pub nft_mints: Vec<Pubkey>,
+    /// Groups of [metadata, one receiver per creator listed in it],
+    /// creator wallets for SOL, creator token accounts for SPL
+    pub royalty_accounts: &'a [AccountInfo<'info>],
+}
+
+/// Pays `amount` from `source` to `seller` less the protocol fee and royalties
+pub fn distribute_payment<'info>(
+    source: &PaymentSource<'_, 'info>,
+    mint: Option<Pubkey>,
+    amount: u64,
+    seller: &AccountInfo<'info>,
+    fees: SaleFees<'_, 'info>,
+) -> Result<()> {
+    if payment_receiver_owner(fees.fee_receiver, mint)? != fees.config.fee_receiver {
+        return err!(Errors::FeeAccountsMismatch);
+    }
+    let fee = protocol_fee(amount, fees.fee_bps);
+    source.pay(fees.fee_receiver, fee)?;
+
+    let mut royalties: u64 = 0;
+    let mut paid_mints: Vec<Pubkey> = vec![];
+    let mut royalty_infos = fees.royalty_accounts.iter();
+    while let Some(metadata_info) = royalty_infos.next() {
+        if *metadata_info.owner != mpl_token_metadata::id() {
+            return err!(Errors::RoyaltyAccountsMismatch);
+        }
+        let metadata = Metadata::from_account_info(metadata_info)?;
+        let (metadata_key, _) = Pubkey::find_program_address(
+            &[
+                mpl_token_metadata::state::PREFIX.as_bytes(),
+                mpl_token_metadata::id().as_ref(),
+                metadata.mint.as_ref(),
+            ],
+            &mpl_token_metadata::id(),
+        );
+        if metadata_key != metadata_info.key()
+            || !fees.nft_mints.contains(&metadata.mint)
+            || paid_mints.contains(&metadata.mint)
+        {
+            return err!(Errors::RoyaltyAccountsMismatch);
+        }
+        paid_mints.push(metadata.mint);
+
+        let royalty = nft_royalty(
+            amount,
+            fees.nft_mints.len(),
+            metadata.data.seller_fee_basis_points,
+        );
+        for creator in metadata.data.creators.unwrap_or_default() {
+            let creator_info = royalty_infos
+                .next()
+                .ok_or(Errors::RoyaltyAccountsMismatch)?;
+            if payment_receiver_owner(creator_info, mint)? != creator.address {
+                return err!(Errors::RoyaltyAccountsMismatch);
+            }
+            let creator_royalty = creator_royalty(royalty, creator.share);
+            source.pay(creator_info, creator_royalty)?;
+            royalties += creator_royalty;
+        }
+    }
+    if fees.config.royalties_enforced && paid_mints.len() != fees.nft_mints.len() {
+        return err!(Errors::RoyaltiesMissing);
+    }
+
+    let proceeds = amount
+        .checked_sub(fee)
+        .and_then(|rest| rest.checked_sub(royalties))
+        .ok_or(Errors::FeesExceedPayment)?;
+    source.pay(seller, proceeds)
+}
+
+/// Wallet that ends up holding a payment sent to `info`: the account itself for SOL,
+/// the token account owner for SPL
+pub fn payment_receiver_owner(info: &AccountInfo, mint: Option<Pubkey>) -> Result<Pubkey> {
+    match mint {
+        None => Ok(info.key()),
+        Some(mint) => {
+            let token_account: Account<TokenAccount> = Account::try_from(info)?;
+            if token_account.mint != mint {
+                return err!(Errors::PaymentAccountsMismatch);
+            }
+            Ok(token_account.owner)
+        }
+    }
+}
+
+/// `amount * numerator / denominator` without intermediate overflow
+pub fn share_of(amount: u64, numerator: u64, denominator: u64) -> u64 {
+    (u128::from(amount) * u128::from(numerator) / u128::from(denominator)) as u64
+}
+
+/// Protocol fee on `amount` at `fee_bps`
+pub fn protocol_fee(amount: u64, fee_bps: u16) -> u64 {
+    share_of(amount, u64::from(fee_bps), u64::from(MAX_BPS))
+}
+
+/// Royalty owed for one of `nft_count` NFTs bought together for `amount`,
+/// each NFT is priced at an even share of the payment
+pub fn nft_royalty(amount: u64, nft_count: usize, seller_fee_basis_points: u16) -> u64 {
+    share_of(
+        amount / nft_count as u64,
+        u64::from(seller_fee_basis_points),
+        u64::from(MAX_BPS),
+    )
+}
+
+/// A creator's cut of `royalty`, `share` is the creator's percentage from the metadata
+pub fn creator_royalty(royalty: u64, share: u8) -> u64 {
+    share_of(royalty, u64::from(share), 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_of_rounds_down() {
+        assert_eq!(share_of(1_000, 250, 10_000), 25);
+        assert_eq!(share_of(999, 1, 10), 99);
+        assert_eq!(share_of(0, 500, 10_000), 0);
+    }
+
+    #[test]
+    fn share_of_does_not_overflow() {
+        assert_eq!(share_of(u64::MAX, 10_000, 10_000), u64::MAX);
+        assert_eq!(share_of(u64::MAX, 1, 2), u64::MAX / 2);
+    }
+
+    #[test]
+    fn protocol_fee_is_in_basis_points() {
+        assert_eq!(protocol_fee(1_000_000, 250), 25_000);
+        assert_eq!(protocol_fee(1_000_000, 0), 0);
+        assert_eq!(protocol_fee(1_000_000, MAX_BPS), 1_000_000);
+    }
+
+    #[test]
+    fn nft_royalty_uses_an_even_share_per_nft() {
+        // Single NFT: 5% of the whole payment
+        assert_eq!(nft_royalty(1_000_000, 1, 500), 50_000);
+        // Four NFTs: 5% of a quarter of the payment each
+        assert_eq!(nft_royalty(1_000_000, 4, 500), 12_500);
+        // The even share rounds down before the royalty is applied
+        assert_eq!(nft_royalty(10, 3, MAX_BPS), 3);
+    }
+
+    #[test]
+    fn creator_royalties_split_by_share() {
+        let royalty = nft_royalty(1_000_000, 1, 1_000);
+        let cuts: Vec<u64> = [60u8, 40]
+            .iter()
+            .map(|share| creator_royalty(royalty, *share))
+            .collect();
+        assert_eq!(cuts, vec![60_000, 40_000]);
+        assert_eq!(creator_royalty(royalty, 0), 0);
+    }
+
+    #[test]
+    fn royalties_never_exceed_the_payment() {
+        // Every NFT at 100% royalty, split three ways, still rounds down to at most the payment
+        let amount = 1_000_003;
+        let nft_count = 3;
+        let royalties: u64 = (0..nft_count)
+            .map(|_| {
+                let royalty = nft_royalty(amount, nft_count, MAX_BPS);
+                [33u8, 33, 34]
+                    .iter()
+                    .map(|share| creator_royalty(royalty, *share))
+                    .sum::<u64>()
+            })
+            .sum();
+        assert!(royalties <= amount);
+    }
+
+    #[test]
+    fn seller_proceeds_after_fee_and_royalties() {
+        let amount = 1_000_000;
+        let fee = protocol_fee(amount, 250);
+        let royalties = nft_royalty(amount, 2, 500) * 2;
+        let proceeds = amount
+            .checked_sub(fee)
+            .and_then(|rest| rest.checked_sub(royalties));
+        assert_eq!(proceeds, Some(1_000_000 - 25_000 - 50_000));
+
+        // A fee and royalties above the payment leave nothing for the seller
+        let royalties = nft_royalty(amount, 1, MAX_BPS);
+        let proceeds = amount
+            .checked_sub(fee)
+            .and_then(|rest| rest.checked_sub(royalties));
+        assert_eq!(proceeds, None);
+    }
+}
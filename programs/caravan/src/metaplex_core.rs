@@ -0,0 +1,66 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program::invoke_signed,
+};
+
+// Metaplex Core doesn't ship a crate we can build against, so the TransferV1 CPI is built by hand
+pub mod mpl_core {
+    use super::*;
+    declare_id!("CoREENxT6tW1HoK8ypY1SxRMZTcVPm7R94rH4PZNhX7d");
+}
+
+// TransferV1 instruction index, followed by `compression_proof: None`
+const TRANSFER_V1_DATA: [u8; 2] = [14, 0];
+
+/**
+ * Transfers a Core asset from `authority` to `new_owner`.
+ * `collection` has to be passed for assets in a collection, Core runs the collection's
+ * plugins (royalty rule sets included) against the transfer.
+ * Optional accounts that aren't passed are replaced by the Core program id.
+ */
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_core<'info>(
+    core_program: &AccountInfo<'info>,
+    asset: &AccountInfo<'info>,
+    collection: Option<&AccountInfo<'info>>,
+    payer: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    new_owner: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let collection = collection.unwrap_or(core_program);
+
+    let accounts = vec![
+        AccountMeta::new(asset.key(), false),
+        AccountMeta::new_readonly(collection.key(), false),
+        AccountMeta::new(payer.key(), true),
+        AccountMeta::new_readonly(authority.key(), true),
+        AccountMeta::new_readonly(new_owner.key(), false),
+        AccountMeta::new_readonly(system_program.key(), false),
+        // log wrapper
+        AccountMeta::new_readonly(core_program.key(), false),
+    ];
+
+    let account_infos = vec![
+        asset.clone(),
+        collection.clone(),
+        payer.clone(),
+        authority.clone(),
+        new_owner.clone(),
+        system_program.clone(),
+        core_program.clone(),
+    ];
+
+    invoke_signed(
+        &Instruction {
+            program_id: mpl_core::ID,
+            accounts,
+            data: TRANSFER_V1_DATA.to_vec(),
+        },
+        &account_infos,
+        signer_seeds,
+    )?;
+    Ok(())
+}